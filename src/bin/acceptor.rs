@@ -1,19 +1,27 @@
 //! Code for acceptor.
 //!
+//! ```sh
+//! cargo run --bin acceptor -- [uuid]
+//! ```
 //!
+//! Pass the uuid printed by a previous run to restart that acceptor with its old state.
 
 use dc_project::{
+    kv::KvOp,
     paxos::{acceptor, dir::acceptor_init},
     NodeId,
 };
+use sqlite::Connection;
 use std::env;
-use uuid::Uuid;
 
 fn main() {
-    // let id = env::args().nth(1).unwrap().parse::<usize>().unwrap();
-    let id = *Uuid::new_v4().as_bytes();
-    println!("Acceptor {:?}", id);
+    let id = match env::args().nth(1) {
+        Some(s) => s.parse::<NodeId>().unwrap(),
+        None => NodeId::new(),
+    };
+    println!("Acceptor {}", id);
 
-    let sock = acceptor_init(id);
-    acceptor::listen(NodeId { id }, sock.1, sock.0);
+    let db = Connection::open("paxos.db").unwrap();
+    let (handler, listener, addr) = acceptor_init(id, &db).unwrap();
+    acceptor::listen::<KvOp>(id, addr, listener, handler, db);
 }
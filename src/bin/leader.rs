@@ -1,14 +1,41 @@
 //! Code for paxos leader
 //!
+//! ```sh
+//! cargo run --bin leader -- [uuid] [--join] [--lease]
+//! ```
+//!
+//! `--join` starts it outside the config, for adding it with `paxos_admin`.
+//! `--lease` has it hold a read lease, for replicas started with `--lease`.
 
 use std::env;
 
-use dc_project::paxos::{dir::leader_init, leader};
+use dc_project::{
+    kv::KvOp,
+    paxos::{
+        acceptor,
+        dir::leader_init,
+        leader::{self, LeaderConfig},
+    },
+    NodeId,
+};
+use sqlite::Connection;
 
 fn main() {
-    let id = env::args().nth(1).unwrap().parse::<usize>().unwrap();
+    let args = env::args().collect::<Vec<_>>();
+    let join = args.iter().any(|a| a == "--join");
+    let lease = args.iter().any(|a| a == "--lease");
+    let id = match args.iter().skip(1).find(|a| !a.starts_with("--")) {
+        Some(s) => s.parse::<NodeId>().unwrap(),
+        None => NodeId::new(),
+    };
     println!("Leader {}", id);
 
-    let sock = leader_init(id);
-    leader::listen(id, sock.0, sock.1);
+    let db = Connection::open("paxos.db").unwrap();
+    let (handler, listener, addr) = leader_init::<KvOp>(id, &db).unwrap();
+    let config = LeaderConfig {
+        join,
+        lease: lease.then_some(acceptor::MAX_LEASE),
+        ..Default::default()
+    };
+    leader::listen(id, listener, handler, addr, config);
 }
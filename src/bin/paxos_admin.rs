@@ -0,0 +1,46 @@
+//! Reconfiguration for a running Paxos cluster.
+//!
+//! ```sh
+//! cargo run --bin paxos_admin -- (replica addr) (leader addrs) (acceptor addrs)
+//! ```
+//!
+//! Address lists are comma separated, and replace the current sets outright.
+//! Goes to one replica, which proposes it like any other request. The new config
+//! takes over some slots after the one it gets decided at.
+//!
+//! New leaders should be started with `--join`, so they sit out until they're in.
+
+use std::{env, net::SocketAddr, thread, time::Duration};
+
+use dc_project::{
+    kv::KvOp,
+    paxos::{Config, Message},
+};
+use message_io::{network::Transport, node};
+use serde_json::to_vec;
+
+fn addrs(list: &str) -> Vec<SocketAddr> {
+    list.split(',').map(|a| a.parse().unwrap()).collect()
+}
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    let replica = args[1].parse::<SocketAddr>().unwrap();
+    let config = Config {
+        epoch: 0,
+        leaders: addrs(&args[2]),
+        acceptors: addrs(&args[3]),
+    };
+
+    let (handler, _listener) = node::split::<()>();
+    let ep = handler
+        .network()
+        .connect(Transport::Udp, replica)
+        .unwrap()
+        .0;
+    let msg: Message<KvOp> = Message::Reconfigure(config.clone());
+    handler.network().send(ep, &to_vec(&msg).unwrap());
+    // Let it get out the door.
+    thread::sleep(Duration::from_millis(100));
+    println!("Sent {:?} to {}", config, replica);
+}
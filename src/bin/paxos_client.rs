@@ -7,6 +7,7 @@
 use std::env;
 
 use dc_project::{
+    kv::KvOp,
     paxos::{
         dir::{client_init, get_all_replicas},
         Command, Message,
@@ -15,6 +16,7 @@ use dc_project::{
 };
 use rand::seq::SliceRandom;
 use serde_json::to_vec;
+use sqlite::Connection;
 
 /// ```sh
 /// cargo run --bin paxos_client -- (client_id)
@@ -24,17 +26,23 @@ use serde_json::to_vec;
 fn main() {
     let params = Params::new();
     let (handler, _listener) = client_init();
-    let reps = get_all_replicas(handler.clone());
+    let db = Connection::open("paxos.db").unwrap();
+    let reps = get_all_replicas(handler.clone(), &db);
     let rep = reps.choose(&mut rand::thread_rng()).unwrap();
     println!("Sending to {:?}", rep);
     let client_id = env::args().nth(1).unwrap().parse::<usize>().unwrap();
     let u = rand::distributions::Uniform::from(0.0..1.0);
+    let session = rand::random::<u64>();
     for i in 0..params.k {
         let val = rand::random::<u64>();
         let msg = Message::Request(Command {
             client_id,
+            session,
             op_id: i,
-            op: val.to_string(),
+            op: KvOp::Put {
+                key: format!("key{}", i % 10),
+                value: val.to_string(),
+            },
         });
         dbg!(&msg);
         handler.network().send(*rep, &to_vec(&msg).unwrap());
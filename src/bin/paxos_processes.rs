@@ -8,8 +8,9 @@
 use std::{process::Stdio, thread, time::Duration};
 
 use dc_project::{
+    kv::KvOp,
     paxos::{
-        dir::{client_init, get_all_replicas, ACCEPTOR_COUNT, LEADER_COUNT, REPLICA_COUNT},
+        dir::{client_init, get_all_replicas},
         Command, Message,
     },
     Params,
@@ -17,21 +18,26 @@ use dc_project::{
 use message_io::network::Transport;
 use rand::seq::SliceRandom;
 use serde_json::to_vec;
+use sqlite::Connection;
 use std::process;
 // use serde_json::to_vec;
 
+const LEADER_COUNT: u8 = 3;
+const REPLICA_COUNT: u8 = 3;
+const ACCEPTOR_COUNT: u8 = 3;
+
+/// Every process picks its own id.
 fn main() {
     let params = Params::new();
     let sock = client_init();
-    let reps = get_all_replicas(sock.0.clone());
 
     let mut acc_handles = vec![];
 
-    for i in 0..ACCEPTOR_COUNT {
+    for _ in 0..ACCEPTOR_COUNT {
         let mut cargo = process::Command::new("cargo");
         acc_handles.push(
             cargo
-                .args(["r", "--bin", "acceptor", "--", &i.to_string()])
+                .args(["r", "--bin", "acceptor"])
                 .stdout(Stdio::null())
                 .stderr(Stdio::null())
                 .spawn()
@@ -41,11 +47,11 @@ fn main() {
     thread::sleep(Duration::from_secs(1));
 
     let mut lea_handles = vec![];
-    for i in 0..LEADER_COUNT {
+    for _ in 0..LEADER_COUNT {
         let mut cargo = process::Command::new("cargo");
         lea_handles.push(
             cargo
-                .args(["r", "--bin", "leader", "--", &i.to_string()])
+                .args(["r", "--bin", "leader"])
                 .stdout(Stdio::null())
                 .stderr(Stdio::null())
                 .spawn()
@@ -55,28 +61,35 @@ fn main() {
     thread::sleep(Duration::from_secs(1));
 
     let mut rep_handles = vec![];
-    for i in 0..REPLICA_COUNT {
+    for _ in 0..REPLICA_COUNT {
         let mut cargo = process::Command::new("cargo");
         rep_handles.push(
             cargo
-                .args(["r", "--bin", "replica", "--", &i.to_string()])
+                .args(["r", "--bin", "replica"])
                 .spawn()
                 .unwrap(),
         );
     }
 
     thread::sleep(Duration::from_secs(1));
+    let db = Connection::open("paxos.db").unwrap();
+    let reps = get_all_replicas(sock.0.clone(), &db);
 
     let u = rand::distributions::Uniform::from(0.0..1.0);
 
     let rep = reps.choose(&mut rand::thread_rng()).unwrap();
     let _ = sock.0.network().connect(Transport::FramedTcp, rep.addr());
+    let session = rand::random::<u64>();
     for i in 0..params.k {
         let val = rand::random::<u64>();
         let msg = Message::Request(Command {
             client_id: 0,
+            session,
             op_id: i,
-            op: val.to_string(),
+            op: KvOp::Put {
+                key: format!("key{}", i % 10),
+                value: val.to_string(),
+            },
         });
         sock.0.network().send(*rep, &to_vec(&msg).unwrap());
         params.sleep(u, &mut rand::thread_rng());
@@ -11,64 +11,78 @@ use std::{
 };
 
 use dc_project::{
+    kv::{KvOp, KvStore},
     paxos::{
         acceptor,
-        dir::{
-            acceptor_init, client_init, get_all_replicas, leader_init, replica_init,
-            ACCEPTOR_COUNT, LEADER_COUNT, REPLICA_COUNT,
-        },
-        leader, replica, Command, Message,
+        dir::{acceptor_init, client_init, get_all_replicas, leader_init, replica_init},
+        leader::{self, LeaderConfig},
+        replica, Command, Message,
     },
-    Params,
+    NodeId, Params,
 };
 use message_io::network::Transport;
 use rand::seq::SliceRandom;
 use serde_json::to_vec;
+use sqlite::Connection;
 // use serde_json::to_vec;
 
+const LEADER_COUNT: u8 = 3;
+const REPLICA_COUNT: u8 = 3;
+const ACCEPTOR_COUNT: u8 = 3;
+
 fn main() {
     let params = Params::new();
+    let db = Connection::open("paxos.db").unwrap();
     let sock = client_init();
-    let reps = get_all_replicas(sock.0.clone());
 
     let mut acc_handles = vec![];
-    for i in 0..ACCEPTOR_COUNT {
-        let sock = acceptor_init(i.into());
+    for _ in 0..ACCEPTOR_COUNT {
+        let id = NodeId::new();
+        let (h, l, addr) = acceptor_init(id, &db).unwrap();
+        let conn = Connection::open("paxos.db").unwrap();
         acc_handles.push(thread::spawn(move || {
-            acceptor::listen(i.into(), sock.1, sock.0);
+            acceptor::listen::<KvOp>(id, addr, l, h, conn);
         }));
     }
     thread::sleep(Duration::from_secs(1));
 
     let mut lea_handles = vec![];
-    for i in 0..LEADER_COUNT {
-        let sock = leader_init(i.into());
+    for _ in 0..LEADER_COUNT {
+        let id = NodeId::new();
+        let (h, l, addr) = leader_init::<KvOp>(id, &db).unwrap();
         lea_handles.push(thread::spawn(move || {
-            leader::listen(i.into(), sock.0, sock.1);
+            leader::listen(id, l, h, addr, LeaderConfig::default());
         }));
     }
     thread::sleep(Duration::from_secs(1));
     let mut rep_handles = vec![];
-    for i in 0..REPLICA_COUNT {
-        let sock = replica_init(i.into());
+    for _ in 0..REPLICA_COUNT {
+        let id = NodeId::new();
+        let (h, l, addr) = replica_init(id, &db).unwrap();
         rep_handles.push(thread::spawn(move || {
-            replica::listen(i.into(), sock.1, sock.0);
+            replica::listen::<KvStore>(id, addr, l, h, false);
         }));
     }
 
     thread::sleep(Duration::from_secs(1));
+    let reps = get_all_replicas(sock.0.clone(), &db);
 
     let u = rand::distributions::Uniform::from(0.0..1.0);
 
     let rep = reps.choose(&mut rand::thread_rng()).unwrap();
     let _ = sock.0.network().connect(Transport::FramedTcp, rep.addr());
     let init = Instant::now();
+    let session = rand::random::<u64>();
     for i in 0..params.k {
         let val = rand::random::<u64>();
         let msg = Message::Request(Command {
             client_id: 0,
+            session,
             op_id: i,
-            op: val.to_string(),
+            op: KvOp::Put {
+                key: format!("key{}", i % 10),
+                value: val.to_string(),
+            },
         });
         sock.0.network().send(*rep, &to_vec(&msg).unwrap());
         params.sleep(u, &mut rand::thread_rng());
@@ -1,16 +1,35 @@
 //! Code for server.
 //!
+//! ```sh
+//! cargo run --bin raft -- (id) [data_dir] [--join] [--config file]
+//! ```
 //!
+//! Killing and restarting with the same id and data directory is safe.
+//! With `--join`, the server starts outside the cluster and waits to be added with `raft_admin`.
+//! With `--config`, addresses and timings come from that JSON file (see `RaftConfig`)
+//! instead of the local five-server defaults.
 
 use dc_project::{
-    raft::{dir::RAFT_PORT, server},
-    LOOPBACK,
+    kv::KvStore,
+    raft::{
+        dir::RAFT_DATA,
+        server, Config, RaftConfig,
+    },
 };
-use std::{env, net::SocketAddr};
+use std::{env, path::PathBuf};
 
 fn main() {
     let id = env::args().nth(1).unwrap().parse::<usize>().unwrap();
-    println!("Server {}", id);
+    let data_dir = match env::args().nth(2) {
+        Some(d) if !d.starts_with("--") => PathBuf::from(d),
+        _ => PathBuf::from(RAFT_DATA),
+    };
+    let settings = RaftConfig::from_args();
+    let config = match env::args().any(|a| a == "--join") {
+        true => Config::default(),
+        false => settings.initial(),
+    };
+    println!("Server {} on {}", id, settings.addr(id));
 
-    server::run(id, SocketAddr::from((LOOPBACK, RAFT_PORT + id as u16)));
+    server::run_with::<KvStore>(id, &data_dir, &settings, config);
 }
@@ -0,0 +1,56 @@
+//! Membership changes and leadership transfer for a running Raft cluster.
+//!
+//! ```sh
+//! cargo run --bin raft_admin -- (server) learn (id) [addr]
+//! cargo run --bin raft_admin -- (server) add (id) [addr]
+//! cargo run --bin raft_admin -- (server) remove (id)
+//! cargo run --bin raft_admin -- (server) transfer (id)
+//! ```
+//!
+//! Add `--config file` to find servers the way they do.
+//!
+//! Goes to `server`, which passes it on to the leader. One change at a time:
+//! anything sent before the last change is committed gets dropped, so just send it again.
+//!
+//! `learn` brings a server in without a vote. `add` makes it a full member, learner or not.
+//! To replace a machine, start the new one with `--join`, add it as a learner, add it for real
+//! once it has caught up, then remove the old one.
+//! To take the leader down for maintenance, transfer to someone else first.
+
+use std::{env, net::SocketAddr, thread, time::Duration};
+
+use dc_project::{
+    kv::KvOp,
+    raft::{Change, Message, RaftConfig},
+};
+use message_io::{network::Transport, node};
+use serde_json::to_vec;
+
+fn main() {
+    let args = env::args().collect::<Vec<_>>();
+    let settings = RaftConfig::from_args();
+    let server = args[1].parse::<usize>().unwrap();
+    let id = args[3].parse::<usize>().unwrap();
+    let addr = match args.get(4).filter(|a| !a.starts_with("--")) {
+        Some(a) => a.parse::<SocketAddr>().unwrap(),
+        None => settings.addr(id),
+    };
+    let msg: Message<KvOp> = match args[2].as_str() {
+        "learn" => Message::Reconfigure(Change::Learn(id, addr)),
+        "add" => Message::Reconfigure(Change::Add(id, addr)),
+        "remove" => Message::Reconfigure(Change::Remove(id)),
+        "transfer" => Message::Transfer(id),
+        other => panic!("Unknown command {other}."),
+    };
+
+    let (handler, _listener) = node::split::<()>();
+    let ep = handler
+        .network()
+        .connect(Transport::Udp, settings.addr(server))
+        .unwrap()
+        .0;
+    handler.network().send(ep, &to_vec(&msg).unwrap());
+    // Let it get out the door.
+    thread::sleep(Duration::from_millis(100));
+    println!("Sent {:?} to {}", msg, server);
+}
@@ -3,59 +3,119 @@
 //! Thinking each client produces a random value at random intervals and sends it to the replica.
 //!
 //! Which replica? Designated replica, random replica, or all replicas? RANDOM REPLICA.
+//! Then whichever one a `NotLeader` points at.
+//!
+//! Given a group count, talks to `raft_multi` nodes instead. Every request goes to the group
+//! `multi::shard` picks for its key, and each group has its own target.
+//!
+//! Servers are the local defaults, or whatever `--config file` says. If any of them is on
+//! another machine, the client listens on this machine's address so replies can get back.
 
-use std::{env, net::SocketAddr};
+use std::{
+    env,
+    net::SocketAddr,
+    sync::{Arc, Mutex},
+};
 
 use dc_project::{
-    raft::{
-        dir::{RAFT_COUNT, RAFT_PORT},
-        Command, Message,
-    },
+    kv::KvOp,
+    raft::{multi, Command, Message, RaftConfig},
     Params, LOOPBACK,
 };
-use message_io::{network::Transport, node};
+use local_ip_address::local_ip;
+use message_io::{
+    network::{NetEvent, Transport},
+    node::{self, NodeEvent},
+};
 use rand::{
     distributions::{Distribution, Uniform},
     thread_rng,
 };
-use serde_json::to_vec;
+use serde_json::{from_slice, to_vec};
 
 /// ```sh
-/// cargo run --bin raft_client -- (client_id)
+/// cargo run --bin raft_client -- (client_id) [groups] [--config file]
 /// ```
 fn main() {
     let params = Params::new();
     let sock = node::split::<()>();
     let client_id = env::args().nth(1).unwrap().parse::<usize>().unwrap();
-    let addr = SocketAddr::from((LOOPBACK, 10000 + client_id as u16));
+    let groups = env::args()
+        .nth(2)
+        .filter(|g| !g.starts_with("--"))
+        .map(|g| g.parse::<usize>().unwrap());
+    let settings = RaftConfig::from_args();
+    let servers = settings.servers.values().copied().collect::<Vec<_>>();
+    let addr = match servers.iter().all(|s| s.ip().is_loopback()) {
+        true => SocketAddr::from((LOOPBACK, 10000 + client_id as u16)),
+        false => SocketAddr::from((local_ip().unwrap(), 10000 + client_id as u16)),
+    };
     let _ = sock.0.network().listen(Transport::Udp, addr).unwrap();
 
     let u = Uniform::from(0.0..1.0);
-    let v = Uniform::from(0..RAFT_COUNT);
+    let v = Uniform::from(0..servers.len());
     let rep_idx = v.sample(&mut thread_rng());
     let rep = sock
         .0
         .network()
-        .connect(
-            Transport::Udp,
-            SocketAddr::from((LOOPBACK, RAFT_PORT + rep_idx as u16)),
-        )
+        .connect(Transport::Udp, servers[rep_idx])
         .unwrap()
         .0;
 
     println!("Sending to {:?}", rep);
 
+    // Follow the hints. A request turned away goes to someone at random.
+    let target = Arc::new(Mutex::new(vec![rep; groups.unwrap_or(1)]));
+    let handler = sock.0.clone();
+    let current = target.clone();
+    let _replies = sock.1.for_each_async(move |event| {
+        let NodeEvent::Network(NetEvent::Message(_, buf)) = event else {
+            return;
+        };
+        let msg = match groups {
+            Some(_) => from_slice::<(usize, Message<KvOp>)>(buf),
+            None => from_slice::<Message<KvOp>>(buf).map(|m| (0, m)),
+        };
+        let Ok((g, Message::NotLeader { cmd, hint })) = msg else {
+            return;
+        };
+        let addr = hint.unwrap_or_else(|| servers[v.sample(&mut thread_rng())]);
+        let ep = handler.network().connect(Transport::Udp, addr).unwrap().0;
+        if let Some(t) = current.lock().unwrap().get_mut(g) {
+            *t = ep;
+        }
+        if hint.is_none() {
+            handler.network().send(ep, &request(groups.map(|_| g), cmd));
+        }
+    });
+
     // let u = rand::distributions::Uniform::from(0.0..1.0);
+    let session = rand::random::<u64>();
     for i in 0..params.k {
         let val = rand::random::<u64>();
-        let msg = Message::Request(Command {
+        let key = format!("key{}", i % 10);
+        let g = groups.map(|n| multi::shard(&key, n));
+        let cmd = Command {
             client: addr,
+            session,
             op_id: i,
-            op: val.to_string(),
-        });
-        dbg!(&msg);
-        sock.0.network().send(rep, &to_vec(&msg).unwrap());
+            op: KvOp::Put {
+                key,
+                value: val.to_string(),
+            },
+        };
+        let rep = target.lock().unwrap()[g.unwrap_or(0)];
+        sock.0.network().send(rep, &request(g, cmd));
         params.sleep(u, &mut rand::thread_rng());
     }
     println!("Done.");
 }
+
+/// A request on the wire, tagged with its group under Multi-Raft.
+fn request(group: Option<usize>, cmd: Command<KvOp>) -> Vec<u8> {
+    let msg = Message::Request(cmd);
+    match group {
+        Some(g) => to_vec(&(g, msg)).unwrap(),
+        None => to_vec(&msg).unwrap(),
+    }
+}
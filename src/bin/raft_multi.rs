@@ -0,0 +1,38 @@
+//! Code for a Multi-Raft node.
+//!
+//! ```sh
+//! cargo run --bin raft_multi -- (id) (groups) [data_dir] [--config file]
+//! ```
+//!
+//! Runs server `id` of groups `0..groups`, all on the one port. Every group starts out as
+//! the cluster in the config, or the default one. Point `raft_client` at it with the same
+//! `groups`.
+
+use dc_project::{
+    kv::KvStore,
+    raft::{dir::RAFT_DATA, multi, RaftConfig},
+};
+use std::{env, path::PathBuf};
+
+fn main() {
+    let id = env::args().nth(1).unwrap().parse::<usize>().unwrap();
+    let groups = env::args().nth(2).unwrap().parse::<usize>().unwrap();
+    let data_dir = match env::args().nth(3) {
+        Some(d) if !d.starts_with("--") => PathBuf::from(d),
+        _ => PathBuf::from(RAFT_DATA),
+    };
+    let settings = RaftConfig::from_args();
+    println!(
+        "Server {} of {} groups on {}",
+        id,
+        groups,
+        settings.addr(id)
+    );
+
+    multi::run_groups::<KvStore>(
+        id,
+        &data_dir,
+        &settings,
+        (0..groups).map(|g| (g, settings.initial())).collect(),
+    );
+}
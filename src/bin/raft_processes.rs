@@ -9,8 +9,9 @@ use std::net::SocketAddr;
 use std::time::Duration;
 use std::{process, thread};
 
-use dc_project::raft::dir::{raft_init, RAFT_COUNT, RAFT_PORT};
-use dc_project::raft::{server, Command, Message};
+use dc_project::raft::dir::{RAFT_COUNT, RAFT_PORT};
+use dc_project::raft::{Command, Message};
+use dc_project::kv::KvOp;
 use dc_project::{Params, LOOPBACK};
 use message_io::network::Transport;
 use message_io::node;
@@ -52,12 +53,17 @@ fn main() {
 
     thread::sleep(Duration::from_secs(3));
 
+    let session = rand::random::<u64>();
     for i in 0..params.k {
         let val = rand::random::<u64>();
         let msg = Message::Request(Command {
             client: SocketAddr::from((LOOPBACK, 10000)),
+            session,
             op_id: i,
-            op: val.to_string(),
+            op: KvOp::Put {
+                key: format!("key{}", i % 10),
+                value: val.to_string(),
+            },
         });
         sock.0.network().send(rep, &to_vec(&msg).unwrap());
         params.sleep(u, &mut rand::thread_rng());
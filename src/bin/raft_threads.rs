@@ -9,8 +9,12 @@ use std::net::SocketAddr;
 use std::thread;
 use std::time::{Duration, Instant};
 
-use dc_project::raft::dir::{raft_init, RAFT_COUNT, RAFT_PORT};
+use dc_project::raft::{
+    dir::{raft_init, RAFT_COUNT, RAFT_PORT},
+    RaftConfig,
+};
 use dc_project::raft::{Command, Message};
+use dc_project::kv::{KvOp, KvStore};
 use dc_project::{Params, LOOPBACK};
 use message_io::network::Transport;
 use message_io::node;
@@ -26,7 +30,7 @@ fn main() {
         .network()
         .listen(Transport::Udp, SocketAddr::from((LOOPBACK, 10000)))
         .unwrap();
-    let handles = raft_init(); // Server threads spawn
+    let handles = raft_init::<KvStore>(&RaftConfig::default()); // Server threads spawn
     let u = Uniform::from(0.0..1.0);
     let v = Uniform::from(0..RAFT_COUNT);
     let rep_idx = v.sample(&mut thread_rng());
@@ -43,12 +47,17 @@ fn main() {
     thread::sleep(Duration::from_secs(3));
 
     let init = Instant::now();
+    let session = rand::random::<u64>();
     for i in 0..params.k {
         let val = rand::random::<u64>();
         let msg = Message::Request(Command {
             client: SocketAddr::from((LOOPBACK, 10000)),
+            session,
             op_id: i,
-            op: val.to_string(),
+            op: KvOp::Put {
+                key: format!("key{}", i % 10),
+                value: val.to_string(),
+            },
         });
         sock.0.network().send(rep, &to_vec(&msg).unwrap());
         params.sleep(u, &mut rand::thread_rng());
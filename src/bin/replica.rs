@@ -1,14 +1,28 @@
 //! Code for replica
 //!
+//! ```sh
+//! cargo run --bin replica -- [uuid] [--lease]
+//! ```
 //!
+//! `--lease` sends reads to the leaders first, for leaders started with `--lease`.
 
-use dc_project::paxos::{dir::replica_init, replica};
+use dc_project::{
+    kv::KvStore,
+    paxos::{dir::replica_init, replica},
+    NodeId,
+};
+use sqlite::Connection;
 use std::env;
 
 fn main() {
-    let id = env::args().nth(1).unwrap().parse::<usize>().unwrap();
+    let lease = env::args().any(|a| a == "--lease");
+    let id = match env::args().skip(1).find(|a| !a.starts_with("--")) {
+        Some(s) => s.parse::<NodeId>().unwrap(),
+        None => NodeId::new(),
+    };
     println!("Replica {}", id);
 
-    let sock = replica_init(id);
-    replica::listen(id, sock.1, sock.0);
+    let db = Connection::open("paxos.db").unwrap();
+    let (handler, listener, addr) = replica_init(id, &db).unwrap();
+    replica::listen::<KvStore>(id, addr, listener, handler, lease);
 }
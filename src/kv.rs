@@ -0,0 +1,155 @@
+//! A replicated key-value store.
+//!
+//! Plugs into either protocol as the [`StateMachine`]:
+//! `paxos::replica::listen::<KvStore>` or `raft::server::run::<KvStore>`.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{Operation, StateMachine};
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum KvOp {
+    Get {
+        key: String,
+    },
+    /// Overwrites whatever was there.
+    Put {
+        key: String,
+        value: String,
+    },
+    Delete {
+        key: String,
+    },
+    /// Sets `key` to `new` only if it currently holds `expected`. `None` means absent.
+    Cas {
+        key: String,
+        expected: Option<String>,
+        new: String,
+    },
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
+pub enum KvError {
+    /// CAS lost. Contains what the key actually holds.
+    Mismatch(Option<String>),
+}
+
+/// `Get` returns the current value, everything else returns the previous one.
+pub type KvResult = Result<Option<String>, KvError>;
+
+impl Operation for KvOp {
+    type Output = KvResult;
+
+    fn is_read(&self) -> bool {
+        matches!(self, KvOp::Get { .. })
+    }
+}
+
+/// BTreeMap so that snapshots come out the same on every replica.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq)]
+pub struct KvStore {
+    map: BTreeMap<String, String>,
+}
+
+impl KvStore {
+    /// Local, unreplicated read.
+    pub fn get(&self, key: &str) -> Option<&String> {
+        self.map.get(key)
+    }
+}
+
+impl StateMachine for KvStore {
+    type Op = KvOp;
+
+    fn apply(&mut self, op: &KvOp) -> KvResult {
+        match op {
+            KvOp::Get { key } => Ok(self.map.get(key).cloned()),
+            KvOp::Put { key, value } => Ok(self.map.insert(key.clone(), value.clone())),
+            KvOp::Delete { key } => Ok(self.map.remove(key)),
+            KvOp::Cas { key, expected, new } => {
+                let current = self.map.get(key);
+                if current != expected.as_ref() {
+                    return Err(KvError::Mismatch(current.cloned()));
+                }
+                Ok(self.map.insert(key.clone(), new.clone()))
+            }
+        }
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        *self = serde_json::from_slice(snapshot).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cas(key: &str, expected: Option<&str>, new: &str) -> KvOp {
+        KvOp::Cas {
+            key: key.into(),
+            expected: expected.map(Into::into),
+            new: new.into(),
+        }
+    }
+
+    #[test]
+    fn cas_only_swaps_what_it_expects() {
+        let mut kv = KvStore::default();
+        assert_eq!(kv.apply(&cas("k", None, "a")), Ok(None));
+        assert_eq!(
+            kv.apply(&cas("k", None, "b")),
+            Err(KvError::Mismatch(Some("a".into())))
+        );
+        assert_eq!(
+            kv.apply(&cas("k", Some("b"), "c")),
+            Err(KvError::Mismatch(Some("a".into())))
+        );
+        assert_eq!(kv.apply(&cas("k", Some("a"), "c")), Ok(Some("a".into())));
+        assert_eq!(kv.get("k"), Some(&"c".to_string()));
+
+        kv.apply(&KvOp::Delete { key: "k".into() }).unwrap();
+        assert_eq!(
+            kv.apply(&cas("k", Some("c"), "d")),
+            Err(KvError::Mismatch(None))
+        );
+        assert_eq!(kv.apply(&cas("k", None, "d")), Ok(None));
+    }
+
+    #[test]
+    fn delete_returns_what_was_there() {
+        let mut kv = KvStore::default();
+        let put = KvOp::Put {
+            key: "k".into(),
+            value: "v".into(),
+        };
+        let delete = KvOp::Delete { key: "k".into() };
+        assert_eq!(kv.apply(&put), Ok(None));
+        assert_eq!(kv.apply(&delete), Ok(Some("v".into())));
+        assert_eq!(kv.apply(&delete), Ok(None));
+        assert_eq!(kv.apply(&KvOp::Get { key: "k".into() }), Ok(None));
+        assert!(!KvOp::Delete { key: "k".into() }.is_read());
+    }
+
+    #[test]
+    fn snapshots_round_trip() {
+        let mut kv = KvStore::default();
+        for i in 0..10 {
+            kv.apply(&KvOp::Put {
+                key: format!("k{i}"),
+                value: i.to_string(),
+            })
+            .unwrap();
+        }
+        let mut other = KvStore::default();
+        other.restore(&kv.snapshot());
+        assert_eq!(other, kv);
+        assert_eq!(other.snapshot(), kv.snapshot());
+    }
+}
@@ -1,18 +1,37 @@
-use std::{fmt::Display, fs::File, io::Read, net::SocketAddr, thread, time::Duration};
+use std::{
+    fmt::{Debug, Display},
+    fs::File,
+    hash::Hash,
+    io::Read,
+    net::SocketAddr,
+    str::FromStr,
+    thread,
+    time::Duration,
+};
 
-use message_io::node::NodeHandler;
+use message_io::{adapters::udp::MAX_LOCAL_PAYLOAD_LEN, node::NodeHandler};
 use rand::{
     distributions::{Distribution, Uniform},
     rngs::ThreadRng,
 };
-use serde::{Deserialize, Serialize};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
 use sqlite::Connection;
 use uuid::Uuid;
 
 pub const LOOPBACK: [u8; 4] = [127, 0, 0, 1];
 
+/// Most bytes one message can take. Everything goes over UDP, one message per datagram,
+/// and message_io won't send a bigger one. That's only about 9 KB on macOS.
+pub const MAX_DATAGRAM: usize = MAX_LOCAL_PAYLOAD_LEN;
+/// Left over in a datagram for whatever goes around a batch, or a piece of a snapshot.
+pub const HEADROOM: usize = 512;
+/// Snapshots go out in pieces of this many bytes. JSON writes a byte as up to 4 characters.
+pub const SNAPSHOT_CHUNK: usize = (MAX_DATAGRAM - HEADROOM) / 4;
+
+pub mod kv;
 pub mod paxos;
 pub mod raft;
+pub mod session;
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Identity {
@@ -68,15 +87,63 @@ impl Params {
     }
 }
 
+/// Whatever a client asks the replicated state machine to do.
+/// It goes over the wire and into logs, hence all the bounds.
+pub trait Operation:
+    Debug + Clone + PartialEq + Eq + Hash + Serialize + DeserializeOwned + Send + Sync + 'static
+{
+    /// What the client gets back.
+    type Output: Debug + Clone + Serialize + DeserializeOwned + Send + Sync + 'static;
+
+    /// Doesn't change the state. These can skip the log when the protocol knows how.
+    fn is_read(&self) -> bool {
+        false
+    }
+}
+
+/// The thing being replicated. Paxos replicas and Raft servers both drive one of these.
+///
+/// `apply` must be deterministic: every replica applies the same ops in the same order,
+/// and they all have to end up in the same state.
+pub trait StateMachine: Default + Send + 'static {
+    type Op: Operation;
+
+    /// Perform one decided operation.
+    fn apply(&mut self, op: &Self::Op) -> <Self::Op as Operation>::Output;
+
+    /// Serialize the whole state.
+    fn snapshot(&self) -> Vec<u8>;
+
+    /// Replace the whole state with one produced by `snapshot`.
+    fn restore(&mut self, snapshot: &[u8]);
+}
+
+/// Plain strings, echoed straight back.
+impl Operation for String {
+    type Output = Result<String, String>;
+}
+
 /// Right now this is just a `usize`, but it can really be anything. The rest of the code is general enough.
+///
+/// Trivial state machine: it ignores every op and just echoes it back.
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash, Default, Copy)]
 pub struct ReplicaState {
     n: usize,
 }
 
-impl ReplicaState {
-    pub fn triv(s: String) -> impl Fn(&ReplicaState) -> (ReplicaState, Result<String, String>) {
-        move |q| (*q, Ok(s.clone()))
+impl StateMachine for ReplicaState {
+    type Op = String;
+
+    fn apply(&mut self, op: &String) -> Result<String, String> {
+        Ok(op.clone())
+    }
+
+    fn snapshot(&self) -> Vec<u8> {
+        serde_json::to_vec(self).unwrap()
+    }
+
+    fn restore(&mut self, snapshot: &[u8]) {
+        *self = serde_json::from_slice(snapshot).unwrap();
     }
 }
 
@@ -93,6 +160,23 @@ impl NodeId {
     }
 }
 
+impl Display for NodeId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", Uuid::from_bytes(self.id))
+    }
+}
+
+/// Parses what `Display` prints, so ids can be passed around on the command line.
+impl FromStr for NodeId {
+    type Err = uuid::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            id: *Uuid::parse_str(s)?.as_bytes(),
+        })
+    }
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq)]
 pub struct Entry {
     pub id: NodeId,
@@ -1,8 +1,11 @@
 #![allow(dead_code)]
 
-use std::net::SocketAddr;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::SocketAddr,
+    time::{Duration, Instant},
+};
 
-use itertools::Itertools;
 use message_io::{
     network::NetEvent,
     node::{NodeHandler, NodeListener},
@@ -11,23 +14,38 @@ use serde_json::{from_slice, to_vec};
 use sqlite::Connection;
 
 use crate::{
-    paxos::{Ballot, Message, Proposal}, Entry, Identity, NodeId
+    paxos::{Ballot, Message, Proposal}, Entry, Identity, NodeId, Operation, HEADROOM, MAX_DATAGRAM,
 };
 
-use super::dir::{remember_node, teach};
+use super::dir::{
+    accept, acceptor_store, checkpoint, promise, recall_acceptor, remember_node, teach,
+};
 
 // type AcceptList = Arc<Mutex<Vec<Proposal>>>;
 
+/// Longest lease an acceptor will hand out, whatever the leader asks for.
+/// Also how long a restarted acceptor refuses new promises, since it can't remember its leases.
+pub const MAX_LEASE: Duration = Duration::from_secs(2);
+
 /// Acceptor struct.
-struct Acceptor {
+/// Never looks inside the operations, it just needs to be able to store and ship them.
+struct Acceptor<O: Operation> {
     /// Used to be just a lil number. Unique among all acceptors.
     /// Now uuid.
     pub id: NodeId,
-    /// The current ballot number of the acceptor. Important thing.
-    pub ballot: Option<Ballot>,
+    /// The current ballot number of the acceptor, per epoch. Important thing.
+    /// Each config gets its own, as if it had its own acceptor.
+    pub ballots: HashMap<usize, Ballot>,
 
-    /// All the stuff so far.
-    pub accepted: Vec<Proposal>,
+    /// All the stuff so far, the latest accept for each slot.
+    /// Both of these are mirrored in the db, and written there before any reply goes out.
+    pub accepted: BTreeMap<usize, Proposal<O>>,
+    /// Every replica has executed everything below this. Nothing down there is kept or accepted.
+    pub low: usize,
+
+    /// Until `lease_until`, nobody but the holder gets a promise out of us.
+    lease_holder: Option<NodeId>,
+    lease_until: Instant,
 
     /// This is us.
     // pub sock: UdpSocket,
@@ -37,68 +55,127 @@ struct Acceptor {
     db: Connection,
 }
 
-impl Acceptor {
-    pub fn new(id: NodeId, addr: SocketAddr, handler: NodeHandler<()>) -> Acceptor {
-        Acceptor {
+impl<O: Operation> Acceptor<O> {
+    /// Picks up whatever this id promised and accepted in a previous life.
+    /// A fresh id just starts out empty.
+    pub fn with_conn(id: NodeId, addr: SocketAddr, handler: NodeHandler<()>, db: Connection) -> Self {
+        acceptor_store(&db).unwrap();
+        let (ballots, low, accepted) = recall_acceptor(&db, id).unwrap();
+        // Coming back from a crash, we might have had a lease out.
+        let lease_until = if ballots.is_empty() {
+            Instant::now()
+        } else {
+            Instant::now() + MAX_LEASE
+        };
+        Self {
             id,
-            ballot: None,
-            accepted: vec![],
+            ballots,
+            accepted,
+            low,
+            lease_holder: None,
+            lease_until,
             handler,
             addr,
-            db: Connection::open("paxos.db").unwrap(),
+            db,
         }
     }
 
-    pub fn with_conn(id: NodeId, addr: SocketAddr, handler: NodeHandler<()>, db: Connection) -> Acceptor {
-        Acceptor {
-            id,
-            ballot: None,
-            accepted: vec![],
-            handler,
-            addr,
-            db,
+    /// Accepts of `epoch` from slot `from` on, as many as fit in a datagram.
+    /// If that's not all of them, also the slot the rest start at.
+    fn get_latest_accepts(&self, epoch: usize, from: usize) -> (Vec<Proposal<O>>, Option<usize>) {
+        let mut out = vec![];
+        let mut bytes = HEADROOM;
+        let ours = self.accepted.range(from..).filter(|(_, p)| p.ballot.epoch == epoch);
+        for (slot, p) in ours {
+            bytes += to_vec(p).unwrap().len() + 1;
+            if !out.is_empty() && bytes > MAX_DATAGRAM {
+                return (out, Some(*slot));
+            }
+            out.push(p.clone());
         }
+        (out, None)
     }
 
-    fn get_latest_accepts(&self) -> Vec<Proposal> {
-        self.accepted
-            .iter()
-            .max_set()
-            .into_iter()
-            .cloned()
-            .collect()
+    fn leased_to_other(&self, lid: NodeId) -> bool {
+        Instant::now() < self.lease_until && self.lease_holder != Some(lid)
     }
 
     /// Promise
-    fn receive_p1(&mut self, ballot: Ballot) -> Message {
+    ///
+    /// Nothing at all while someone else holds a lease. The scout keeps retransmitting until it runs out.
+    fn receive_p1(&mut self, ballot: Ballot, from: usize) -> Option<Message<O>> {
+        if self.leased_to_other(ballot.leader_id) {
+            return None;
+        }
         // Just do it.
-        if self.ballot.is_none() || ballot > self.ballot.unwrap() {
-            self.ballot = Some(ballot);
+        let current = self.ballots.get(&ballot.epoch).copied();
+        if current.is_none() || ballot > current.unwrap() {
+            // Panicking here is fine, the leader just sees a dead acceptor.
+            promise(&self.db, self.id, &ballot).unwrap();
+            self.ballots.insert(ballot.epoch, ballot);
         }
 
         // Send that damnation message.
-        Message::Phase1b(
+        let (accepts, more) = self.get_latest_accepts(ballot.epoch, from);
+        Some(Message::Phase1b(
             ballot.leader_id,
             self.id,
-            self.ballot.unwrap(),
-            self.get_latest_accepts(),
-        )
+            self.ballots[&ballot.epoch],
+            accepts,
+            more,
+        ))
     }
 
     /// Accept
-    fn receive_p2(&mut self, leader_id: NodeId, proposal: Proposal) -> Message {
-        if Some(proposal.ballot) == self.ballot {
-            self.accepted.push(proposal.clone());
+    ///
+    /// Slots below the checkpoint are decided and done with. Whoever is proposing there is behind, ignore them.
+    fn receive_p2(&mut self, leader_id: NodeId, proposal: Proposal<O>) -> Option<Message<O>> {
+        if proposal.slot < self.low {
+            return None;
         }
-        Message::Phase2b(leader_id, self.id, proposal.ballot)
+        // Anything not below the promise is fine. Whoever sent it got past phase 1 with that ballot.
+        let epoch = proposal.ballot.epoch;
+        let current = self.ballots.get(&epoch).copied();
+        if current.is_none() || proposal.ballot >= current.unwrap() {
+            if current != Some(proposal.ballot) {
+                promise(&self.db, self.id, &proposal.ballot).unwrap();
+                self.ballots.insert(epoch, proposal.ballot);
+            }
+            accept(&self.db, self.id, &proposal).unwrap();
+            self.accepted.insert(proposal.slot, proposal);
+        }
+        // Our ballot, not theirs, so that the commander can tell it has been preempted.
+        Some(Message::Phase2b(leader_id, self.id, self.ballots[&epoch]))
+    }
+
+    /// Garbage collection.
+    fn receive_checkpoint(&mut self, slot: usize) -> Option<Message<O>> {
+        if slot > self.low {
+            checkpoint(&self.db, self.id, slot).unwrap();
+            self.low = slot;
+            self.accepted = self.accepted.split_off(&slot);
+        }
+        None
+    }
+
+    /// Lease. Only for whoever we're currently promised to.
+    fn receive_lease(&mut self, lid: NodeId, ballot: Ballot, round: usize, len: Duration) -> Option<Message<O>> {
+        if self.ballots.get(&ballot.epoch) != Some(&ballot) || self.leased_to_other(lid) {
+            return None;
+        }
+        self.lease_holder = Some(lid);
+        self.lease_until = Instant::now() + len.min(MAX_LEASE);
+        Some(Message::LeaseGrant(lid, self.id, ballot, round))
     }
 
     /// Mux
-    fn handle(&mut self, req: Message) -> Message {
+    fn handle(&mut self, req: Message<O>) -> Option<Message<O>> {
         // dbg!(&req);
         match req {
-            Message::Phase1a(_num, ballot) => self.receive_p1(ballot),
+            Message::Phase1a(_num, ballot, from) => self.receive_p1(ballot, from),
             Message::Phase2a(lid, prop) => self.receive_p2(lid, prop),
+            Message::Checkpoint(slot) => self.receive_checkpoint(slot),
+            Message::Lease(lid, ballot, round, len) => self.receive_lease(lid, ballot, round, len),
             _ => unreachable!(),
         }
     }
@@ -106,13 +183,21 @@ impl Acceptor {
 
 /// This is the main loop for the acceptor.
 /// Acceptors are pretty dumb, so there's not much going on here.
-pub fn listen(id: NodeId, addr: SocketAddr, listener: NodeListener<()>, handler: NodeHandler<()>) {
-    let mut acc = Acceptor::new(id, addr, handler);
+///
+/// Restarting with the same `id` recovers the old promises and accepts from the db.
+pub fn listen<O: Operation>(
+    id: NodeId,
+    addr: SocketAddr,
+    listener: NodeListener<()>,
+    handler: NodeHandler<()>,
+    db: Connection,
+) {
+    let mut acc = Acceptor::<O>::with_conn(id, addr, handler, db);
     // println!("Inited acceptor {id}.");
 
     let _ = listener.for_each_async(move |event| match event.network() {
         NetEvent::Message(endpoint, buf) => {
-            let msg = from_slice::<Message>(&buf).unwrap();
+            let msg = from_slice::<Message<O>>(&buf).unwrap();
             match msg {
                 Message::Identify(entry, reply) => {
                     let Ok(_) = remember_node(&acc.db, &entry) else {
@@ -124,9 +209,10 @@ pub fn listen(id: NodeId, addr: SocketAddr, listener: NodeListener<()>, handler:
                     }
                 }
                 _ => {
-                    let res = acc.handle(msg);
-                    // Mutable borrow. 
-                    acc.handler.network().send(endpoint, &to_vec(&res).unwrap());
+                    if let Some(res) = acc.handle(msg) {
+                        // Mutable borrow.
+                        acc.handler.network().send(endpoint, &to_vec(&res).unwrap());
+                    }
                 },
             }
         }
@@ -141,3 +227,110 @@ pub fn listen(id: NodeId, addr: SocketAddr, listener: NodeListener<()>, handler:
         } // _ => {}
     });
 }
+
+#[cfg(test)]
+mod tests {
+    use std::{env, fs};
+
+    use message_io::node;
+
+    use super::*;
+    use crate::paxos::{Command, Decree};
+
+    fn proposal(slot: usize, ballot: Ballot, op: &str) -> Proposal<String> {
+        let command = Command { client_id: 0, session: 0, op_id: slot, op: op.to_string() };
+        Proposal { slot, ballot, command: Decree::Op(command) }
+    }
+
+    fn open(id: NodeId, path: &std::path::Path) -> Acceptor<String> {
+        let (handler, _) = node::split::<()>();
+        let addr = "127.0.0.1:0".parse().unwrap();
+        Acceptor::with_conn(id, addr, handler, Connection::open(path).unwrap())
+    }
+
+    #[test]
+    fn restarts_with_what_it_promised_and_accepted() {
+        let path = env::temp_dir().join(format!("acceptor-{}.db", NodeId::new()));
+        let (id, leader, other) = (NodeId::new(), NodeId::new(), NodeId::new());
+        let b1 = Ballot::new(0, 1, leader);
+        let b2 = Ballot::new(0, 2, leader);
+
+        let mut acc = open(id, &path);
+        acc.receive_p1(b1, 0).unwrap();
+        // Retransmits of the same accept, then a higher ballot for the same slot.
+        for _ in 0..3 {
+            acc.receive_p2(leader, proposal(3, b1, "a")).unwrap();
+        }
+        acc.receive_p2(leader, proposal(3, b2, "b")).unwrap();
+        acc.receive_p2(leader, proposal(5, b2, "c")).unwrap();
+        acc.receive_checkpoint(4);
+        drop(acc);
+
+        let mut acc = open(id, &path);
+        assert_eq!(acc.ballots[&0], b2);
+        assert_eq!(acc.low, 4);
+        assert_eq!(acc.accepted.keys().copied().collect::<Vec<_>>(), vec![5]);
+        // Might have had a lease out, so nobody else gets a promise for a while.
+        assert!(acc.receive_p1(Ballot::new(0, 9, other), 0).is_none());
+        acc.lease_until = Instant::now();
+
+        let Some(Message::Phase1b(_, _, b, accepts, None)) = acc.receive_p1(b2, 0) else {
+            panic!("no promise for the old leader");
+        };
+        assert_eq!(b, b2);
+        assert_eq!(accepts.len(), 1);
+        assert_eq!(accepts[0].command, proposal(5, b2, "c").command);
+        // Decided and forgotten, not accepted again.
+        assert!(acc.receive_p2(leader, proposal(2, b2, "d")).is_none());
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn retransmitted_accepts_are_kept_once() {
+        let path = env::temp_dir().join(format!("acceptor-{}.db", NodeId::new()));
+        let (id, leader) = (NodeId::new(), NodeId::new());
+        let b = Ballot::new(0, 1, leader);
+
+        let mut acc = open(id, &path);
+        for _ in 0..10 {
+            acc.receive_p2(leader, proposal(1, b, "a")).unwrap();
+        }
+        let mut q = acc.db.prepare("SELECT count(*) AS n FROM accepted;").unwrap();
+        q.next().unwrap();
+        assert_eq!(q.read::<i64, _>("n").unwrap(), 1);
+        assert_eq!(acc.get_latest_accepts(0, 0).0.len(), 1);
+        drop(q);
+        drop(acc);
+        fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn promises_come_in_pages() {
+        let path = env::temp_dir().join(format!("acceptor-{}.db", NodeId::new()));
+        let (id, leader) = (NodeId::new(), NodeId::new());
+        let b = Ballot::new(0, 1, leader);
+        let big = "x".repeat(MAX_DATAGRAM / 4);
+
+        let mut acc = open(id, &path);
+        for slot in 0..10 {
+            acc.receive_p2(leader, proposal(slot, b, &big)).unwrap();
+        }
+        let mut from = 0;
+        let mut slots = vec![];
+        loop {
+            let Some(Message::Phase1b(_, _, _, accepts, more)) = acc.receive_p1(b, from) else {
+                panic!("no promise");
+            };
+            let msg = Message::Phase1b(leader, id, b, accepts.clone(), more);
+            assert!(to_vec(&msg).unwrap().len() <= MAX_DATAGRAM);
+            slots.extend(accepts.iter().map(|p| p.slot));
+            match more {
+                Some(next) => from = next,
+                None => break,
+            }
+        }
+        assert_eq!(slots, (0..10).collect::<Vec<_>>());
+        drop(acc);
+        fs::remove_file(path).unwrap();
+    }
+}
@@ -1,15 +1,21 @@
-use std::net::SocketAddr;
+use std::{
+    collections::{BTreeMap, HashMap},
+    net::{IpAddr, SocketAddr},
+};
 
 use local_ip_address::local_ip;
 use message_io::{
     adapters::udp::UdpConnectConfig, network::{Endpoint, Transport}, node::{self, NodeHandler, NodeListener}
 };
-use serde_json::to_vec;
+use serde_json::{from_str, to_string, to_vec};
 use sqlite::{Connection, Row, State};
 
-use crate::{Entry, Identity, NodeId};
+use crate::{Entry, Identity, NodeId, Operation};
 
-use super::{leader::Agent, Ballot, Message};
+use super::{
+    leader::{Agent, AgentTimer, ScoutSignal},
+    Ballot, Config, Message, Proposal,
+};
 
 pub const LEADER_PORT: u16 = 4000;
 pub const SCOUT_PORT: u16 = 4500;
@@ -25,12 +31,38 @@ pub const GLOBAL_NODES_QUERY: &'static str = "SELECT * FROM nodes WHERE kind = :
 
 pub const REMEMBER: &'static str = "INSERT INTO nodes VALUES (:id, :ip, :kind, :port);";
 pub const ALL_NODES: &'static str = "SELECT (ip, port) FROM nodes;";
+pub const FIND_NODE: &str = "SELECT * FROM nodes WHERE id = :id;";
+
+// Acceptor state. Ballots and proposals are stored as json, same as on the wire.
+pub const ACCEPTOR_STORE: &str = "
+    CREATE TABLE IF NOT EXISTS promises (acceptor BLOB NOT NULL, epoch INTEGER NOT NULL, ballot TEXT NOT NULL, PRIMARY KEY (acceptor, epoch));
+    CREATE TABLE IF NOT EXISTS accepted (acceptor BLOB NOT NULL, slot INTEGER NOT NULL, proposal TEXT NOT NULL, PRIMARY KEY (acceptor, slot));
+    CREATE TABLE IF NOT EXISTS checkpoints (acceptor BLOB PRIMARY KEY, slot INTEGER NOT NULL);
+";
+pub const PROMISE: &str = "INSERT OR REPLACE INTO promises VALUES (:acceptor, :epoch, :ballot);";
+// Only the latest accept for a slot matters. Retransmissions just overwrite it.
+pub const ACCEPT: &str = "INSERT OR REPLACE INTO accepted VALUES (:acceptor, :slot, :proposal);";
+pub const RECALL_PROMISE: &str = "SELECT ballot FROM promises WHERE acceptor = :acceptor;";
+pub const RECALL_ACCEPTED: &str =
+    "SELECT proposal FROM accepted WHERE acceptor = :acceptor ORDER BY rowid;";
+pub const CHECKPOINT: &str = "INSERT OR REPLACE INTO checkpoints VALUES (:acceptor, :slot);";
+pub const FORGET: &str = "DELETE FROM accepted WHERE acceptor = :acceptor AND slot < :slot;";
+pub const RECALL_CHECKPOINT: &str = "SELECT slot FROM checkpoints WHERE acceptor = :acceptor;";
+
+/// Promises (one per epoch), checkpoint and accepts (one per slot) of an acceptor,
+/// as found on disk.
+pub type AcceptorState<O> = (HashMap<usize, Ballot>, usize, BTreeMap<usize, Proposal<O>>);
+
+/// Handler, listener and address of a freshly set up leader.
+pub type LeaderSock<O> = (NodeHandler<Agent<O>>, NodeListener<Agent<O>>, SocketAddr);
 
 /* pub const LEADER_COUNT: u8 = 3;
 pub const REPLICA_COUNT: u8 = 3;
 pub const ACCEPTOR_COUNT: u8 = 3; */
 
-pub fn scout_init(acceptors: &Vec<Endpoint>) -> (NodeHandler<Ballot>, NodeListener<Ballot>) {
+pub fn scout_init(
+    acceptors: &Vec<Endpoint>,
+) -> (NodeHandler<ScoutSignal>, NodeListener<ScoutSignal>) {
     let (scout_h, scout_l) = node::split();
     for a in acceptors.iter() {
         scout_h.network().connect(Transport::Udp, a.addr()).unwrap();
@@ -38,7 +70,9 @@ pub fn scout_init(acceptors: &Vec<Endpoint>) -> (NodeHandler<Ballot>, NodeListen
     (scout_h, scout_l)
 }
 
-pub fn commander_init(acceptors: &Vec<Endpoint>) -> (NodeHandler<()>, NodeListener<()>) {
+pub fn commander_init(
+    acceptors: &Vec<Endpoint>,
+) -> (NodeHandler<AgentTimer>, NodeListener<AgentTimer>) {
     let (commander_h, commander_l) = node::split();
     for a in acceptors.iter() {
         commander_h
@@ -102,8 +136,96 @@ pub(crate) fn remember_node(
     q.next()
 }
 
+/// Where a node was last seen, if it has ever declared itself.
+fn find_node(db: &Connection, id: NodeId) -> Option<SocketAddr> {
+    let mut q = db.prepare(FIND_NODE).unwrap();
+    q.bind((":id", &id.id[..])).unwrap();
+    let row = q.into_iter().next()?.unwrap();
+    let ip = row.read::<&str, _>("ip").parse::<IpAddr>().ok()?;
+    let port = row.read::<i64, _>("port");
+    Some(SocketAddr::from((ip, port as u16)))
+}
+
+/// Creates the acceptor tables if this is the first acceptor to use the db.
+pub(crate) fn acceptor_store(db: &Connection) -> Result<(), sqlite::Error> {
+    db.execute(ACCEPTOR_STORE)
+}
+
+/// Must hit the disk before the Phase1b goes out.
+pub(crate) fn promise(
+    db: &Connection,
+    acceptor: NodeId,
+    ballot: &Ballot,
+) -> Result<State, sqlite::Error> {
+    let mut q = db.prepare(PROMISE).unwrap();
+    q.bind((":acceptor", &acceptor.id[..])).unwrap();
+    q.bind((":epoch", ballot.epoch as i64)).unwrap();
+    q.bind((":ballot", &*to_string(ballot).unwrap())).unwrap();
+
+    q.next()
+}
+
+/// Must hit the disk before the Phase2b goes out.
+pub(crate) fn accept<O: Operation>(
+    db: &Connection,
+    acceptor: NodeId,
+    proposal: &Proposal<O>,
+) -> Result<State, sqlite::Error> {
+    let mut q = db.prepare(ACCEPT).unwrap();
+    q.bind((":acceptor", &acceptor.id[..])).unwrap();
+    q.bind((":slot", proposal.slot as i64)).unwrap();
+    q.bind((":proposal", &*to_string(proposal).unwrap()))
+        .unwrap();
+
+    q.next()
+}
+
+/// Drops every accept below `slot`. One transaction, so a crash can't leave the checkpoint
+/// ahead of what was actually dropped or the other way around.
+pub(crate) fn checkpoint(db: &Connection, acceptor: NodeId, slot: usize) -> Result<(), sqlite::Error> {
+    db.execute("BEGIN;")?;
+    for sql in [CHECKPOINT, FORGET] {
+        let mut q = db.prepare(sql)?;
+        q.bind((":acceptor", &acceptor.id[..]))?;
+        q.bind((":slot", slot as i64))?;
+        while q.next()? != State::Done {}
+    }
+    db.execute("COMMIT;")
+}
+
+/// Everything an acceptor promised and accepted before it went down.
+pub(crate) fn recall_acceptor<O: Operation>(
+    db: &Connection,
+    acceptor: NodeId,
+) -> Result<AcceptorState<O>, sqlite::Error> {
+    let mut q = db.prepare(RECALL_PROMISE)?;
+    q.bind((":acceptor", &acceptor.id[..]))?;
+    let mut ballots = HashMap::new();
+    for row in q.into_iter() {
+        let b = from_str::<Ballot>(row?.read::<&str, _>("ballot")).unwrap();
+        ballots.insert(b.epoch, b);
+    }
+
+    let mut q = db.prepare(RECALL_CHECKPOINT)?;
+    q.bind((":acceptor", &acceptor.id[..]))?;
+    let low = match q.into_iter().next() {
+        Some(row) => row?.read::<i64, _>("slot") as usize,
+        None => 0,
+    };
+
+    let mut q = db.prepare(RECALL_ACCEPTED)?;
+    q.bind((":acceptor", &acceptor.id[..]))?;
+    let mut accepted = BTreeMap::new();
+    for row in q.into_iter() {
+        let p = from_str::<Proposal<O>>(row?.read::<&str, _>("proposal")).unwrap();
+        accepted.insert(p.slot, p);
+    }
+
+    Ok((ballots, low, accepted))
+}
+
 fn identify(entry: Entry) {
-    let msg = Message::Identify(entry, false);
+    let msg: Message = Message::Identify(entry, false);
     let buf = to_vec(&msg).unwrap();
 
     // This is a one-time node pair that is used only for the Identify operation.
@@ -121,7 +243,7 @@ fn identify(entry: Entry) {
 
 pub(crate) fn teach(entry: Entry) {
     let addr = entry.addr;
-    let msg = Message::Identify(entry, false);
+    let msg: Message = Message::Identify(entry, false);
     let buf = to_vec(&msg).unwrap();
     // Might remove extra node.
     let (handler, _) = node::split::<()>();
@@ -157,10 +279,10 @@ pub fn replica_init(
     Ok((out.0, out.1, addr))
 }
 
-pub fn leader_init(
+pub fn leader_init<O: Operation>(
     id: NodeId,
     db: &Connection,
-) -> Result<(NodeHandler<Agent>, NodeListener<Agent>, SocketAddr), sqlite::Error> {
+) -> Result<LeaderSock<O>, sqlite::Error> {
     let out = node::split();
     let local_things = get_all_local_nodes(db, Identity::Leader);
     let port = LEADER_PORT + local_things.len() as u16;
@@ -191,9 +313,15 @@ pub fn acceptor_init(
     db: &Connection,
 ) -> Result<(NodeHandler<()>, NodeListener<()>, SocketAddr), sqlite::Error> {
     let out = node::split::<()>();
-    let local_things = get_all_local_nodes(db, Identity::Acceptor);
-    let port = ACCEPTOR_PORT + local_things.len() as u16;
-    let addr = declare_self(db, id, Identity::Acceptor, port)?;
+    // A restarted acceptor comes back where it was, so the others still know where to find it.
+    let addr = match find_node(db, id) {
+        Some(addr) => addr,
+        None => {
+            let local_things = get_all_local_nodes(db, Identity::Acceptor);
+            let port = ACCEPTOR_PORT + local_things.len() as u16;
+            declare_self(db, id, Identity::Acceptor, port)?
+        }
+    };
     out.0
         .network()
         .listen(
@@ -214,6 +342,32 @@ pub fn acceptor_init(
     Ok((out.0, out.1, addr))
 }
 
+/// Epoch 0 is whoever is in the directory. Changes after that go through the log.
+pub fn initial_config(db: &Connection) -> Config {
+    let addrs = |kind| {
+        get_all_global_nodes(db, kind)
+            .into_iter()
+            .map(|i| {
+                let ip = i.read::<&str, _>("ip").parse::<IpAddr>().unwrap();
+                let port = i.read::<i64, _>("port");
+                SocketAddr::from((ip, port as u16))
+            })
+            .collect()
+    };
+    Config {
+        epoch: 0,
+        leaders: addrs(Identity::Leader),
+        acceptors: addrs(Identity::Acceptor),
+    }
+}
+
+pub fn connect_all<Y>(handler: &NodeHandler<Y>, addrs: &[SocketAddr]) -> Vec<Endpoint> {
+    addrs
+        .iter()
+        .map(|a| handler.network().connect(Transport::Udp, *a).unwrap().0)
+        .collect()
+}
+
 pub fn get_all_leaders<Y>(handler: NodeHandler<Y>, db: &Connection) -> Vec<Endpoint> {
     let all_nodes = get_all_global_nodes(db, Identity::Leader);
     all_nodes
@@ -273,3 +427,28 @@ pub fn get_all_acceptors<Y>(handler: NodeHandler<Y>, db: &Connection) -> Vec<End
         })
         .collect()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn initial_config_reads_addresses() {
+        let db = Connection::open(":memory:").unwrap();
+        db.execute("CREATE TABLE nodes (id BLOB, ip TEXT, kind TEXT, port INTEGER);").unwrap();
+        let nodes = [
+            (Identity::Leader, "127.0.0.1:4001"),
+            (Identity::Acceptor, "10.1.2.3:8001"),
+            (Identity::Acceptor, "[::1]:8002"),
+            (Identity::Replica, "127.0.0.1:6001"),
+        ];
+        for (kind, addr) in nodes {
+            remember_node(&db, &Entry::new(NodeId::new(), kind, addr.parse().unwrap())).unwrap();
+        }
+        let config = initial_config(&db);
+        assert_eq!(config.epoch, 0);
+        assert_eq!(config.leaders, vec!["127.0.0.1:4001".parse().unwrap()]);
+        let acceptors = ["10.1.2.3:8001".parse().unwrap(), "[::1]:8002".parse().unwrap()];
+        assert_eq!(config.acceptors, acceptors);
+    }
+}
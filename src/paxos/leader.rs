@@ -1,56 +1,147 @@
-use std::{collections::HashMap, net::SocketAddr, sync::Arc, thread, time::Duration};
+use std::{
+    collections::{HashMap, HashSet},
+    net::SocketAddr,
+    sync::Arc,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
 
 use message_io::{
     network::{Endpoint, NetEvent},
     node::{NodeEvent, NodeHandler, NodeListener},
 };
 
+use rand::distributions::{Distribution, Uniform};
 use serde_json::to_vec;
 use sqlite::Connection;
 
-use crate::{paxos::dir::commander_init, Entry, Identity, NodeId};
+use crate::{paxos::dir::commander_init, Entry, Identity, NodeId, Operation};
 
 use super::{
-    dir::{get_all_acceptors, get_all_replicas, remember_node, scout_init, teach},
-    Ballot, Message, Proposal,
+    acceptor,
+    dir::{connect_all, get_all_replicas, initial_config, remember_node, scout_init, teach},
+    Ballot, Command, Config, Decree, Message, Proposal,
 };
 
 /// 'Return type' of a Scout or Commander thread.
 /// Sent through a channel to the main thread.
+///
+/// The leader's own timers come through here too.
 #[derive(Debug, Clone)]
-pub enum Agent {
+pub enum Agent<O: Operation> {
     Committed,
-    Adopted(Ballot, HashMap<usize, Vec<Proposal>>),
+    Adopted(Ballot, HashMap<usize, Vec<Proposal<O>>>),
     Preempted(Ballot),
+    /// No majority before the deadline. Scouts send just the ballot, commanders also the proposal.
+    TimedOut(Ballot, Option<Proposal<O>>),
+    /// Time to tell the other leaders we're alive.
+    Heartbeat,
+    /// Backoff over. Only the most recent one counts.
+    Wake(usize),
+    /// Check whether it's time to move on to the next config.
+    Switch,
+}
+
+/// Timers inside a commander thread.
+#[derive(Debug, Clone, Copy)]
+pub enum AgentTimer {
+    /// Resend to acceptors that haven't answered.
+    Retransmit,
+    /// Give up.
+    Deadline,
+}
+
+/// Signals to the scout thread.
+/// Timers carry the attempt they were set for, so that old ones can be told apart.
+#[derive(Debug, Clone)]
+pub enum ScoutSignal {
+    /// Start over with this ballot, asking only for pvalues from the slot on. Sent by the leader.
+    Scout(Ballot, usize),
+    /// The config changed. Scout these from now on.
+    Acceptors(Arc<Vec<Endpoint>>),
+    Retransmit(usize),
+    Deadline(usize),
+}
+
+/// Knobs for a leader and its agents.
+#[derive(Debug, Clone, Copy)]
+pub struct LeaderConfig {
+    /// How long an agent waits for an acceptor before asking again.
+    pub retransmit: Duration,
+    /// How long an agent tries to reach a majority before reporting `Agent::TimedOut`.
+    pub agent_deadline: Duration,
+    /// How often leaders heartbeat each other.
+    pub heartbeat: Duration,
+    /// A leader not heard from in this long is considered dead.
+    pub leader_timeout: Duration,
+    /// Bounds for the backoff of leaders that aren't the distinguished one.
+    pub backoff_min: Duration,
+    pub backoff_max: Duration,
+    /// Ask the acceptors for a lease of this length and serve reads without a slot while it holds.
+    /// None turns leases off. Acceptors cap it at `acceptor::MAX_LEASE`.
+    pub lease: Option<Duration>,
+    /// How far apart the clocks of a leader and an acceptor can drift over one lease.
+    /// Shaved off the leader's end of every lease.
+    pub drift: Duration,
+    /// Start outside the config and sit idle until a `Reconfigure` brings us in.
+    pub join: bool,
 }
 
-impl Agent {
+impl Default for LeaderConfig {
+    fn default() -> Self {
+        Self {
+            retransmit: Duration::from_millis(50),
+            agent_deadline: Duration::from_secs(1),
+            heartbeat: Duration::from_millis(100),
+            leader_timeout: Duration::from_millis(500),
+            backoff_min: Duration::from_millis(50),
+            backoff_max: Duration::from_secs(2),
+            lease: None,
+            drift: Duration::from_millis(50),
+            join: false,
+        }
+    }
+}
+
+impl<O: Operation> Agent<O> {
     /// Call this in a separate thread
+    ///
+    /// Phase2a goes out again to every acceptor that hasn't answered, every `config.retransmit`.
+    /// The thread ends once the proposal is decided, preempted or past `config.agent_deadline`.
+    #[allow(clippy::too_many_arguments)]
     pub fn init_commander(
-        prop: Proposal,
+        prop: Proposal<O>,
         acceptors: Arc<Vec<Endpoint>>,
         replicas: Arc<Vec<Endpoint>>,
         // sock: Arc<UdpSocket>,
-        handler: NodeHandler<()>,
-        listener: NodeListener<()>,
-        other_handler: NodeHandler<Agent>,
+        handler: NodeHandler<AgentTimer>,
+        listener: NodeListener<AgentTimer>,
+        other_handler: NodeHandler<Agent<O>>,
         // agent_tx: Arc<Sender<Agent>>,
         lid: NodeId,
+        config: LeaderConfig,
     ) {
         // dbg!("Commander.");
         let mut waitfor = (*acceptors).clone();
         let msg = Message::Phase2a(lid, prop.clone());
+        let buf = to_vec(&msg).unwrap();
 
         for acc in acceptors.iter() {
             // sock.send_to(&to_vec(&msg).unwrap(), acc).await.unwrap();
-            handler.network().send(*acc, &to_vec(&msg).unwrap());
+            handler.network().send(*acc, &buf);
         }
+        handler
+            .signals()
+            .send_with_timer(AgentTimer::Retransmit, config.retransmit);
+        handler
+            .signals()
+            .send_with_timer(AgentTimer::Deadline, config.agent_deadline);
 
         // loop {
         listener.for_each(move |event| {
-            match event.network() {
-                NetEvent::Message(endpoint, message) => {
-                    let msg: Message = serde_json::from_slice(&message).unwrap();
+            match event {
+                NodeEvent::Network(NetEvent::Message(endpoint, message)) => {
+                    let msg: Message<O> = serde_json::from_slice(&message).unwrap();
                     // dbg!(&msg);
                     match msg {
                         Message::Phase2b(_back_lid, _acc_id, blt) => {
@@ -58,7 +149,7 @@ impl Agent {
                                 // Using retain coz remove wants the index.
                                 waitfor.retain(|x| *x != endpoint);
 
-                                if waitfor.len() < acceptors.len() / 2 {
+                                if (waitfor.len() as f64) < acceptors.len() as f64 / 2.0 {
                                     // Majority
                                     let rep_msg =
                                         Message::Decision(prop.slot, prop.command.clone());
@@ -71,18 +162,32 @@ impl Agent {
                                     }
                                     // agent_tx.send(Self::Committed).unwrap();
                                     other_handler.signals().send(Self::Committed);
-                                    return;
+                                    handler.stop();
                                 }
-                            } else {
+                            } else if blt > prop.ballot {
                                 // agent_tx.send(Self::Preempted(blt)).unwrap();
                                 other_handler.signals().send(Self::Preempted(blt));
-                                return;
+                                handler.stop();
                             }
                         }
                         _ => {}
                     }
                 }
-                _ => {}
+                NodeEvent::Network(_) => {}
+                NodeEvent::Signal(AgentTimer::Retransmit) => {
+                    for acc in waitfor.iter() {
+                        handler.network().send(*acc, &buf);
+                    }
+                    handler
+                        .signals()
+                        .send_with_timer(AgentTimer::Retransmit, config.retransmit);
+                }
+                NodeEvent::Signal(AgentTimer::Deadline) => {
+                    other_handler
+                        .signals()
+                        .send(Self::TimedOut(prop.ballot, Some(prop.clone())));
+                    handler.stop();
+                }
             }
         });
         /* let mut buf = vec![0; 1024];
@@ -115,36 +220,43 @@ impl Agent {
         // }
     }
 
+    /// Lives as long as the leader. Each `ScoutSignal::Scout` starts a fresh attempt,
+    /// which ends in exactly one of `Adopted`, `Preempted` or `TimedOut`.
+    ///
+    /// Sits idle until the leader decides it should be scouting at all.
     pub fn init_scout(
         lid: NodeId,
-        mut ballot: Ballot,
-        acceptors: Arc<Vec<Endpoint>>,
-        listener: NodeListener<Ballot>,
-        handler: NodeHandler<Ballot>,
-        other_handler: NodeHandler<Agent>, // communicate with leader.
+        mut acceptors: Arc<Vec<Endpoint>>,
+        listener: NodeListener<ScoutSignal>,
+        handler: NodeHandler<ScoutSignal>,
+        other_handler: NodeHandler<Agent<O>>, // communicate with leader.
+        config: LeaderConfig,
     ) {
         let mut waitfor = (*acceptors).clone();
         // loop {
-        // let mut ballot;
-        let msg = Message::Phase1a(lid, ballot);
+        let mut ballot = Ballot::new(0, 0, lid);
+        let mut from = 0;
+        let mut attempt = 0;
 
-        for acc in acceptors.iter() {
-            // sock.send_to(&to_vec(&msg).unwrap(), acc).await.unwrap();
-            handler.network().send(*acc, &to_vec(&msg).unwrap());
-        }
-
-        let mut pvals = HashMap::<usize, Vec<Proposal>>::new();
+        let mut pvals = HashMap::<usize, Vec<Proposal<O>>>::new();
+        // Acceptors with more accepts than fit in one answer, and where we asked them to go on.
+        let mut asked = HashMap::<SocketAddr, usize>::new();
+        // Whether this attempt has already reported back.
+        let mut settled = true;
         let _ = listener.for_each_async(move |event| {
             match event {
                 NodeEvent::Network(u) => match u {
                     NetEvent::Message(endpoint, message) => {
-                        let msg: Message = serde_json::from_slice(&message).unwrap();
+                        let msg: Message<O> = serde_json::from_slice(&message).unwrap();
                         // dbg!(&msg);
                         match msg {
-                            Message::Phase1b(_lid, _acc_id, blt, accepts) => {
+                            Message::Phase1b(_lid, _acc_id, blt, accepts, more) => {
+                                // Late answers, to this attempt or to an older ballot.
+                                if settled || blt < ballot {
+                                    return;
+                                }
                                 if blt == ballot {
                                     // dbg!(&endpoint);
-                                    waitfor.retain(|x| x.addr() != endpoint.addr());
                                     accepts.iter().for_each(|acc| {
                                         if let Some(p) = pvals.get_mut(&acc.slot) {
                                             p.push(acc.clone());
@@ -154,18 +266,30 @@ impl Agent {
                                     });
 
                                     // dbg!(&waitfor, &pvals, &acceptors);
+                                    // Not done with this one until we have all of its accepts.
+                                    if let Some(next) = more {
+                                        let at = asked.entry(endpoint.addr()).or_insert(from);
+                                        if next > *at {
+                                            *at = next;
+                                            let msg = Message::<O>::Phase1a(lid, ballot, next);
+                                            let buf = to_vec(&msg).unwrap();
+                                            handler.network().send(endpoint, &buf);
+                                        }
+                                        return;
+                                    }
+                                    waitfor.retain(|x| x.addr() != endpoint.addr());
 
                                     if (waitfor.len() as f64) < acceptors.len() as f64 / 2.0 {
                                         // Majority
                                         // dbg!("Majority");
+                                        settled = true;
                                         other_handler
                                             .signals()
                                             .send(Self::Adopted(blt, pvals.clone()));
-                                        return;
                                     }
                                 } else {
+                                    settled = true;
                                     other_handler.signals().send(Self::Preempted(blt));
-                                    return;
                                 }
                             }
                             _ => unreachable!(),
@@ -173,13 +297,50 @@ impl Agent {
                     }
                     _ => {}
                 },
-                NodeEvent::Signal(s) => {
+                NodeEvent::Signal(ScoutSignal::Scout(s, f)) => {
                     ballot = s;
-                    let msg = Message::Phase1a(lid, ballot);
+                    from = f;
+                    attempt += 1;
+                    settled = false;
+                    waitfor = (*acceptors).clone();
+                    pvals.clear();
+                    asked.clear();
+                    let msg: Message<O> = Message::Phase1a(lid, ballot, from);
                     for acc in acceptors.iter() {
                         // sock.send_to(&to_vec(&msg).unwrap(), acc).await.unwrap();
                         handler.network().send(*acc, &to_vec(&msg).unwrap());
                     }
+                    handler
+                        .signals()
+                        .send_with_timer(ScoutSignal::Retransmit(attempt), config.retransmit);
+                    handler
+                        .signals()
+                        .send_with_timer(ScoutSignal::Deadline(attempt), config.agent_deadline);
+                }
+                NodeEvent::Signal(ScoutSignal::Acceptors(accs)) => {
+                    let addrs = accs.iter().map(|a| a.addr()).collect::<Vec<_>>();
+                    connect_all(&handler, &addrs);
+                    acceptors = accs;
+                    // Whatever was in flight was for the old config.
+                    settled = true;
+                }
+                NodeEvent::Signal(ScoutSignal::Retransmit(a)) => {
+                    if a == attempt && !settled {
+                        for acc in waitfor.iter() {
+                            let at = asked.get(&acc.addr()).copied().unwrap_or(from);
+                            let msg: Message<O> = Message::Phase1a(lid, ballot, at);
+                            handler.network().send(*acc, &to_vec(&msg).unwrap());
+                        }
+                        handler
+                            .signals()
+                            .send_with_timer(ScoutSignal::Retransmit(attempt), config.retransmit);
+                    }
+                }
+                NodeEvent::Signal(ScoutSignal::Deadline(a)) => {
+                    if a == attempt && !settled {
+                        settled = true;
+                        other_handler.signals().send(Self::TimedOut(ballot, None));
+                    }
                 }
             }
         });
@@ -187,57 +348,326 @@ impl Agent {
 }
 
 /// Leader struct. Most of the action happens here.
-pub struct Leader {
+pub struct Leader<O: Operation> {
     /// Just a lil number. Unique among all leaders.
     id: NodeId,
     //// Set of all outstanding proposals.
-    proposals: HashMap<usize, Proposal>,
+    proposals: HashMap<usize, Proposal<O>>,
     /// State of the scout.
     active: bool,
     /// Current ballot.
     ballot: Ballot,
+    config: LeaderConfig,
+
+    /// Config we're working for and its first slot,
+    /// and the one after it once we hear of it, with its first slot.
+    membership: Config,
+    start: usize,
+    next: Option<(usize, Config)>,
+
+    /// Checkpoint: every replica has executed all slots below this.
+    low: usize,
+    /// How far each replica says it got. Anything below the furthest one is decided.
+    executed: HashMap<NodeId, usize>,
 
-    handler: NodeHandler<Agent>,
+    /// Whether the scout is out with the current ballot.
+    scouting: bool,
+    /// Last heartbeat from each of the other leaders.
+    seen: HashMap<NodeId, Instant>,
+    /// When we came up. Nobody can be sure they're the distinguished leader before hearing from the rest.
+    since: Instant,
+    /// Current backoff, and the id of the latest `Agent::Wake`.
+    backoff: Duration,
+    wake: usize,
+
+    /// The lease runs out at `lease_until`. Renewed every heartbeat in rounds;
+    /// `granted` collects the acceptors that signed up for the current one.
+    lease_until: Option<Instant>,
+    lease_round: usize,
+    lease_sent: Instant,
+    granted: HashSet<NodeId>,
+    /// Reads that came in while we weren't active. Answered once we are.
+    reads: Vec<(Endpoint, Command<O>)>,
+
+    handler: NodeHandler<Agent<O>>,
     addr: SocketAddr,
     db: Connection,
 }
 
-impl Leader {
-    pub fn new(id: NodeId, handler: NodeHandler<Agent>, addr: SocketAddr) -> Self {
+impl<O: Operation> Leader<O> {
+    pub fn new(id: NodeId, handler: NodeHandler<Agent<O>>, addr: SocketAddr) -> Self {
         Self {
             id,
             proposals: HashMap::new(),
             active: false,
-            ballot: Ballot::new(0, id),
+            ballot: Ballot::new(0, 0, id),
+            config: LeaderConfig::default(),
+            membership: Config::default(),
+            start: 0,
+            next: None,
+            low: 0,
+            executed: HashMap::new(),
+            scouting: false,
+            seen: HashMap::new(),
+            since: Instant::now(),
+            backoff: LeaderConfig::default().backoff_min,
+            wake: 0,
+            lease_until: None,
+            lease_round: 0,
+            lease_sent: Instant::now(),
+            granted: HashSet::new(),
+            reads: vec![],
             handler,
             addr,
             db: Connection::open("paxos.db").unwrap(),
         }
     }
 
-    pub fn with_conn(id: NodeId, handler: NodeHandler<Agent>, db: Connection, addr: SocketAddr) -> Self {
+    pub fn with_conn(id: NodeId, handler: NodeHandler<Agent<O>>, db: Connection, addr: SocketAddr) -> Self {
         Self {
             id,
             proposals: HashMap::new(),
             active: false,
-            ballot: Ballot::new(0, id),
+            ballot: Ballot::new(0, 0, id),
+            config: LeaderConfig::default(),
+            membership: Config::default(),
+            start: 0,
+            next: None,
+            low: 0,
+            executed: HashMap::new(),
+            scouting: false,
+            seen: HashMap::new(),
+            since: Instant::now(),
+            backoff: LeaderConfig::default().backoff_min,
+            wake: 0,
+            lease_until: None,
+            lease_round: 0,
+            lease_sent: Instant::now(),
+            granted: HashSet::new(),
+            reads: vec![],
             handler,
             addr,
             db,
         }
     }
 
-    pub fn update(&mut self, pmax: HashMap<usize, Proposal>) {
+    pub fn update(&mut self, pmax: HashMap<usize, Proposal<O>>) {
         self.proposals.retain(|s, p| match pmax.get(&s) {
             Some(val) => val.command == p.command,
             None => true,
         });
 
         self.proposals.extend(pmax.into_iter());
+        let low = self.low;
+        self.proposals.retain(|s, _| *s >= low);
+    }
+
+    /// First slot nothing has been proposed for.
+    fn next_slot(&self) -> usize {
+        self.proposals.keys().max().map_or(self.low, |s| s + 1).max(self.low)
+    }
+
+    /// A replica reported in. Returns the new checkpoint if all of them are now past the old one.
+    fn executed(&mut self, replica: NodeId, slot: usize, replicas: usize) -> Option<usize> {
+        let e = self.executed.entry(replica).or_default();
+        *e = (*e).max(slot);
+        if self.executed.len() < replicas {
+            return None;
+        }
+        let stable = *self.executed.values().min().unwrap();
+        if stable <= self.low {
+            return None;
+        }
+        self.low = stable;
+        self.proposals.retain(|s, _| *s >= stable);
+        Some(stable)
+    }
+
+    /// Every slot of our epoch below the highest one we know of gets a proposal, no-ops where
+    /// there's nothing else. Replicas run slots strictly in order, so a single hole would stall
+    /// them for good.
+    ///
+    /// Only safe right after adoption: a slot with nothing in pmax can't have been decided.
+    /// Slots before our config started were decided by the last one, and proposals held for the
+    /// next one are none of our business yet.
+    fn fill_holes(&mut self) {
+        let epoch = self.ballot.epoch;
+        let ours = self.proposals.values().filter(|p| p.ballot.epoch == epoch);
+        let Some(top) = ours.map(|p| p.slot).max() else {
+            return;
+        };
+        for slot in self.low.max(self.start)..top {
+            self.proposals.entry(slot).or_insert(Proposal {
+                slot,
+                ballot: self.ballot,
+                command: Decree::Noop,
+            });
+        }
+    }
+
+    fn member(&self) -> bool {
+        self.membership.leaders.contains(&self.addr)
+    }
+
+    /// Whether `p` is ours to decide under the current config: proposed for our epoch,
+    /// and we've been adopted. Later ones wait for the switch, they belong to the next ballot.
+    fn in_charge(&self, p: &Proposal<O>) -> bool {
+        let before_next = self.next.as_ref().is_none_or(|(start, _)| p.slot < *start);
+        self.active && p.ballot.epoch == self.membership.epoch && before_next
+    }
+
+    /// A replica told us about a config. Only the nearest one after ours is kept.
+    fn configure(&mut self, start: usize, config: Config) {
+        if config.epoch <= self.membership.epoch {
+            return;
+        }
+        if self.next.as_ref().is_none_or(|(_, n)| config.epoch < n.epoch) {
+            self.next = Some((start, config));
+        }
+    }
+
+    /// Moves on to the next config once every slot before it is decided. Some replica having
+    /// executed past them is enough, they don't all have to be there.
+    /// Returns it, so that the caller can reconnect.
+    fn switch(&mut self) -> Option<Config> {
+        let decided = self.executed.values().max().copied().unwrap_or(0).max(self.low);
+        match self.next {
+            Some((start, _)) if start <= decided => {}
+            _ => return None,
+        }
+        let (start, config) = self.next.take().unwrap();
+        self.membership = config.clone();
+        self.start = start;
+        self.proposals.retain(|s, _| *s >= start);
+        self.ballot = Ballot::new(config.epoch, 0, self.id);
+        self.active = false;
+        self.scouting = false;
+        self.lease_until = None;
+        self.seen.clear();
+        self.since = Instant::now();
+        self.backoff = self.config.backoff_min;
+        Some(config)
+    }
+
+    /// Tells the other leaders we're alive. Not outside the config, where we'd only keep
+    /// the actual leaders from taking over.
+    fn heartbeat(&self, leaders: &[Endpoint]) {
+        if !self.member() {
+            return;
+        }
+        let buf = to_vec(&Message::<O>::Heartbeat(self.id, self.addr)).unwrap();
+        for l in leaders {
+            self.handler.network().send(*l, &buf);
+        }
+    }
+
+    /// Another leader is alive. Only counts if it's in our config: one that was removed,
+    /// or never added, must not keep the actual leaders from taking over.
+    fn heard_from(&mut self, lid: NodeId, addr: SocketAddr) {
+        if lid != self.id && self.membership.leaders.contains(&addr) {
+            self.seen.insert(lid, Instant::now());
+        }
+    }
+
+    /// The distinguished leader is the live one with the highest id. Only it gets to scout.
+    fn distinguished(&self) -> bool {
+        if !self.member() || self.since.elapsed() < self.config.leader_timeout {
+            return false;
+        }
+        self.seen
+            .iter()
+            .filter(|(_, t)| t.elapsed() < self.config.leader_timeout)
+            .all(|(id, _)| *id < self.id)
+    }
+
+    /// Scout if we're the distinguished leader, otherwise wait a while and check again.
+    fn pursue(&mut self, scout: &NodeHandler<ScoutSignal>) {
+        if !self.member() {
+            return;
+        }
+        if self.distinguished() {
+            self.scouting = true;
+            scout.signals().send(ScoutSignal::Scout(self.ballot, self.low));
+        } else {
+            self.back_off();
+        }
+    }
+
+    /// Randomized exponential backoff. Each call doubles the window, up to `backoff_max`.
+    fn back_off(&mut self) {
+        let u = Uniform::new_inclusive(self.backoff / 2, self.backoff);
+        let delay = u.sample(&mut rand::thread_rng());
+        self.wake += 1;
+        self.handler
+            .signals()
+            .send_with_timer(Agent::Wake(self.wake), delay);
+        self.backoff = (self.backoff * 2).min(self.config.backoff_max);
+    }
+
+    fn holds_lease(&self) -> bool {
+        self.active && self.lease_until.is_some_and(|t| Instant::now() < t)
+    }
+
+    /// Starts a new lease round with every acceptor.
+    fn renew_lease(&mut self, len: Duration, acceptors: &[Endpoint]) {
+        self.lease_round += 1;
+        self.lease_sent = Instant::now();
+        self.granted.clear();
+        let msg = Message::<O>::Lease(self.id, self.ballot, self.lease_round, len);
+        let buf = to_vec(&msg).unwrap();
+        for acc in acceptors.iter() {
+            self.handler.network().send(*acc, &buf);
+        }
+    }
+
+    /// A majority signed up. The lease counts from when we asked, not from when they answered,
+    /// and lasts no longer than the acceptors will hold it.
+    fn lease_granted(&mut self, acc: NodeId, round: usize, total: usize) {
+        let Some(len) = self.config.lease else {
+            return;
+        };
+        if round != self.lease_round {
+            return;
+        }
+        self.granted.insert(acc);
+        if self.granted.len() > total / 2 {
+            let len = len.min(acceptor::MAX_LEASE).saturating_sub(self.config.drift);
+            self.lease_until = Some(self.lease_sent + len);
+        }
+    }
+
+    /// With a lease, nobody else can get anything decided. So once the replica has applied
+    /// every slot we know about, its state is as fresh as it gets.
+    fn answer_read(&self, ep: Endpoint, cmd: Command<O>) {
+        let index = match self.holds_lease() {
+            true => Some(self.next_slot()),
+            false => None,
+        };
+        let msg = Message::ReadIndex(index, cmd);
+        self.handler.network().send(ep, &to_vec(&msg).unwrap());
+    }
+
+    /// Spawns a commander thread for `prop`.
+    fn commission(
+        &self,
+        prop: Proposal<O>,
+        acceptors: &Arc<Vec<Endpoint>>,
+        replicas: &Arc<Vec<Endpoint>>,
+    ) -> JoinHandle<()> {
+        let (h, l) = commander_init(acceptors);
+        let new_acc = acceptors.clone();
+        let new_rep = replicas.clone();
+        let oh = self.handler.clone();
+        let (lid, config) = (self.id, self.config);
+        thread::spawn(move || {
+            Agent::init_commander(prop, new_acc, new_rep, h, l, oh, lid, config)
+        })
     }
 }
 
-pub fn get_pmax(pvals: &HashMap<usize, Vec<Proposal>>) -> HashMap<usize, Proposal> {
+pub fn get_pmax<O: Operation>(
+    pvals: &HashMap<usize, Vec<Proposal<O>>>,
+) -> HashMap<usize, Proposal<O>> {
     pvals
         .into_iter()
         .map(|(slot, prop)| {
@@ -249,15 +679,28 @@ pub fn get_pmax(pvals: &HashMap<usize, Vec<Proposal>>) -> HashMap<usize, Proposa
                     .clone(),
             )
         })
-        .collect::<HashMap<usize, Proposal>>()
+        .collect::<HashMap<usize, Proposal<O>>>()
 }
 
 /// TODO: Add file read for lists.
-pub fn listen(id: NodeId, listener: NodeListener<Agent>, handler: NodeHandler<Agent>, addr: SocketAddr) {
+pub fn listen<O: Operation>(
+    id: NodeId,
+    listener: NodeListener<Agent<O>>,
+    handler: NodeHandler<Agent<O>>,
+    addr: SocketAddr,
+    config: LeaderConfig,
+) {
     let mut leader = Leader::new(id, handler, addr);
-    let acceptors = Arc::new(get_all_acceptors(leader.handler.clone(), &leader.db));
+    leader.config = config;
+    leader.backoff = config.backoff_min;
     thread::sleep(Duration::from_secs(2));
+    leader.membership = initial_config(&leader.db);
+    if config.join {
+        leader.membership.leaders.retain(|a| *a != addr);
+    }
+    let mut acceptors = Arc::new(connect_all(&leader.handler, &leader.membership.acceptors));
     let replicas = Arc::new(get_all_replicas(leader.handler.clone(), &leader.db));
+    let mut leaders = connect_all(&leader.handler, &leader.membership.leaders);
 
     // println!("Inited leader {}", id);
 
@@ -269,78 +712,180 @@ pub fn listen(id: NodeId, listener: NodeListener<Agent>, handler: NodeHandler<Ag
     let sh = scout_h.clone();
 
     let _scout = thread::spawn(move || {
-        Agent::init_scout(leader.id, leader.ballot, new_acc, scout_l, sh, oh);
+        Agent::init_scout(leader.id, new_acc, scout_l, sh, oh, config);
     }); // Sus
 
+    // Give the other leaders a chance to say hi before anyone scouts.
+    leader.since = Instant::now();
+    leader.handler.signals().send(Agent::Heartbeat);
+    leader
+        .handler
+        .signals()
+        .send_with_timer(Agent::Wake(leader.wake), config.leader_timeout);
+
     let _ = listener.for_each_async(move |event| {
         match event {
             NodeEvent::Signal(s) => {
                 // dbg!(&s);
                 match s {
-                    Agent::Adopted(_blt, pvals) => {
+                    Agent::Adopted(blt, pvals) => {
                         // leader.ballot.num = blt.num + 1;
+                        // From before a switch.
+                        if blt != leader.ballot {
+                            return;
+                        }
                         let pmax = get_pmax(&pvals);
                         leader.update(pmax);
+                        leader.fill_holes();
 
                         // This is bad. Too many clones. That said, it is Arc, so maybe we can get away with it.
+                        commanders.retain(|c: &JoinHandle<()>| !c.is_finished());
+                        for (_s, p) in leader.proposals.iter_mut() {
+                            // Whatever ballot it was accepted under, it goes out again under ours.
+                            // Acceptors would turn down anything lower.
+                            // Ones held for the next config keep their epoch.
+                            if p.ballot.epoch == blt.epoch {
+                                p.ballot = leader.ballot;
+                            }
+                        }
+                        leader.active = true;
                         for (_s, p) in leader.proposals.iter() {
-                            // let alt_sock = arc_sock.clone();
-                            let new_acc = acceptors.clone();
-                            let new_rep = replicas.clone();
-                            // let new_tx = agent_tx.clone();
-                            let q = p.clone();
-
-                            let (h, l) = commander_init(&acceptors);
-                            let oh = leader.handler.clone();
-                            commanders.push(thread::spawn(move || {
-                                Agent::init_commander(q, new_acc, new_rep, h, l, oh, leader.id)
-                            }));
+                            if leader.in_charge(p) {
+                                commanders.push(leader.commission(p.clone(), &acceptors, &replicas));
+                            }
                         }
 
-                        leader.active = true;
+                        leader.scouting = false;
+                        leader.backoff = config.backoff_min;
+
+                        for (ep, cmd) in std::mem::take(&mut leader.reads) {
+                            leader.answer_read(ep, cmd);
+                        }
                     }
                     Agent::Preempted(blt) => {
                         if blt > leader.ballot {
                             leader.active = false;
+                            leader.scouting = false;
+                            leader.lease_until = None;
                             leader.ballot.num = blt.num + 1;
                             // Pseudocode restarts the thread here. We just update the ballot. Message passing cheaper than spawning.
-                            scout_h.signals().send(leader.ballot);
+                            // Unless someone more deserving is around, in which case we leave them to it.
+                            leader.pursue(&scout_h);
                         }
                     }
                     Agent::Committed => {} // Not given. WTF.
+                    // Couldn't reach a majority. Try again, unless things have moved on since.
+                    Agent::TimedOut(blt, None) => {
+                        if blt == leader.ballot && !leader.active {
+                            leader.scouting = false;
+                            leader.pursue(&scout_h);
+                        }
+                    }
+                    Agent::TimedOut(blt, Some(prop)) => {
+                        // Below the checkpoint it got decided anyway.
+                        if blt == leader.ballot
+                            && leader.in_charge(&prop)
+                            && prop.slot >= leader.low
+                        {
+                            commanders.retain(|c| !c.is_finished());
+                            commanders.push(leader.commission(prop, &acceptors, &replicas));
+                        }
+                    }
+                    Agent::Heartbeat => {
+                        leader.heartbeat(&leaders);
+                        leader
+                            .handler
+                            .signals()
+                            .send_with_timer(Agent::Heartbeat, config.heartbeat);
+
+                        // Take over if whoever was above us went quiet.
+                        if !leader.active && !leader.scouting && leader.distinguished() {
+                            leader.pursue(&scout_h);
+                        }
+
+                        if let (true, Some(len)) = (leader.active, config.lease) {
+                            leader.renew_lease(len, &acceptors);
+                        }
+                    }
+                    Agent::Wake(w) => {
+                        if w == leader.wake && !leader.active && !leader.scouting {
+                            leader.pursue(&scout_h);
+                        }
+                    }
+                    Agent::Switch => {
+                        let Some(next) = leader.switch() else {
+                            return;
+                        };
+                        acceptors = Arc::new(connect_all(&leader.handler, &next.acceptors));
+                        leaders = connect_all(&leader.handler, &next.leaders);
+                        scout_h.signals().send(ScoutSignal::Acceptors(acceptors.clone()));
+                        // Same as coming up: let the new crowd say hi before anyone scouts.
+                        leader.wake += 1;
+                        leader
+                            .handler
+                            .signals()
+                            .send_with_timer(Agent::Wake(leader.wake), config.leader_timeout);
+                    }
                 }
             }
             NodeEvent::Network(u) => match u {
-                NetEvent::Message(_endpoint, buf) => {
-                    let msg: Message = serde_json::from_slice(&buf).unwrap();
+                NetEvent::Message(endpoint, buf) => {
+                    let msg: Message<O> = serde_json::from_slice(&buf).unwrap();
                     // dbg!(&msg);
                     match msg {
-                        Message::Propose(slot, cmd) => {
+                        Message::Propose(epoch, slot, cmd) => {
                             // if let Some(_) = leader.proposals.get(&slot) {
                             //     // Proposal is lost here. Correctness check.
                             //     return;
                             // }
+                            // Old configs are done with everything they ran.
+                            if slot < leader.low || epoch < leader.membership.epoch {
+                                return;
+                            }
 
+                            // For a config we haven't switched to yet, it's held until we have.
+                            let ballot = match epoch == leader.ballot.epoch {
+                                true => leader.ballot,
+                                false => Ballot::new(epoch, 0, leader.id),
+                            };
                             let prop = Proposal {
                                 slot,
-                                ballot: leader.ballot,
+                                ballot,
                                 command: cmd,
                             };
                             leader.proposals.insert(slot, prop.clone());
 
-                            let new_acc = acceptors.clone();
-                            let new_rep = replicas.clone();
-                            // let new_tx = agent_tx.clone();
-                            let oh = leader.handler.clone();
+                            if leader.in_charge(&prop) {
+                                commanders.retain(|c| !c.is_finished());
+                                commanders.push(leader.commission(prop, &acceptors, &replicas));
+                            }
+                        }
+                        Message::Read(cmd) => {
                             if leader.active {
-                                let (h, l) = commander_init(&acceptors);
-                                commanders.push(thread::spawn(move || {
-                                    Agent::init_commander(
-                                        prop, new_acc, new_rep, h, l, oh, leader.id,
-                                    )
-                                }))
+                                leader.answer_read(endpoint, cmd);
+                            } else {
+                                leader.reads.push((endpoint, cmd));
                             }
                         }
+                        Message::LeaseGrant(_lid, acc, blt, round)
+                            if leader.active && blt == leader.ballot =>
+                        {
+                            leader.lease_granted(acc, round, acceptors.len());
+                        }
+                        Message::Executed(rid, slot) => {
+                            if let Some(low) = leader.executed(rid, slot, replicas.len()) {
+                                let buf = to_vec(&Message::<O>::Checkpoint(low)).unwrap();
+                                for ep in acceptors.iter().chain(replicas.iter()) {
+                                    leader.handler.network().send(*ep, &buf);
+                                }
+                            }
+                            leader.handler.signals().send(Agent::Switch);
+                        }
+                        Message::Configure(start, config) => {
+                            leader.configure(start, config);
+                            leader.handler.signals().send(Agent::Switch);
+                        }
+                        Message::Heartbeat(lid, addr) => leader.heard_from(lid, addr),
                         Message::Identify(entry, reply) => {
                             let Ok(_) = remember_node(&leader.db, &entry) else {
                                 panic!("WTF.");
@@ -371,3 +916,443 @@ pub fn listen(id: NodeId, listener: NodeListener<Agent>, handler: NodeHandler<Ag
     });
     // todo!()
 }
+
+#[cfg(test)]
+mod tests {
+    use std::net::UdpSocket;
+
+    use message_io::{
+        events::EventReceiver,
+        network::Transport,
+        node::{self, NodeTask, StoredNodeEvent},
+    };
+    use serde_json::from_slice;
+
+    use super::*;
+
+    /// Acceptors that only answer when told to.
+    fn acceptors(n: usize) -> Vec<UdpSocket> {
+        (0..n)
+            .map(|_| {
+                let s = UdpSocket::bind("127.0.0.1:0").unwrap();
+                s.set_read_timeout(Some(Duration::from_millis(500))).unwrap();
+                s
+            })
+            .collect()
+    }
+
+    fn recv(sock: &UdpSocket) -> Option<(Message<String>, SocketAddr)> {
+        let mut buf = [0; 4096];
+        let (len, from) = sock.recv_from(&mut buf).ok()?;
+        Some((from_slice(&buf[..len]).unwrap(), from))
+    }
+
+    fn quick() -> LeaderConfig {
+        LeaderConfig {
+            retransmit: Duration::from_millis(20),
+            agent_deadline: Duration::from_millis(300),
+            ..Default::default()
+        }
+    }
+
+    /// Stands in for the leader, collecting what the agents report back.
+    struct Reports {
+        handler: NodeHandler<Agent<String>>,
+        rx: EventReceiver<StoredNodeEvent<Agent<String>>>,
+        _task: NodeTask,
+    }
+
+    impl Reports {
+        fn new() -> Self {
+            let (handler, l) = node::split::<Agent<String>>();
+            let (_task, rx) = l.enqueue();
+            Self { handler, rx, _task }
+        }
+
+        fn next(&mut self) -> Agent<String> {
+            loop {
+                let event = self.rx.receive_timeout(Duration::from_secs(2));
+                match event.expect("agent never reported") {
+                    StoredNodeEvent::Signal(a) => return a,
+                    StoredNodeEvent::Network(_) => {}
+                }
+            }
+        }
+    }
+
+    // Otherwise dropping the task waits forever.
+    impl Drop for Reports {
+        fn drop(&mut self) {
+            self.handler.stop();
+        }
+    }
+
+    fn proposal(slot: usize, ballot: Ballot) -> Proposal<String> {
+        let command = Command { client_id: 0, session: 0, op_id: slot, op: "op".to_string() };
+        Proposal { slot, ballot, command: Decree::Op(command) }
+    }
+
+    fn commander(
+        socks: &[UdpSocket],
+        replicas: Arc<Vec<Endpoint>>,
+        prop: Proposal<String>,
+        oh: NodeHandler<Agent<String>>,
+    ) -> JoinHandle<()> {
+        let (h, l) = node::split::<AgentTimer>();
+        let accs = socks
+            .iter()
+            .map(|s| h.network().connect(Transport::Udp, s.local_addr().unwrap()).unwrap().0)
+            .collect::<Vec<_>>();
+        let lid = prop.ballot.leader_id;
+        thread::spawn(move || {
+            Agent::init_commander(prop, Arc::new(accs), replicas, h, l, oh, lid, quick())
+        })
+    }
+
+    #[test]
+    fn commander_retransmits_then_times_out() {
+        let socks = acceptors(3);
+        let mut reports = Reports::new();
+        let prop = proposal(7, Ballot::new(0, 1, NodeId::new()));
+        let agent = commander(&socks, Arc::new(vec![]), prop.clone(), reports.handler.clone());
+
+        // Silent acceptors keep getting the same Phase2a.
+        for _ in 0..3 {
+            let Some((Message::Phase2a(_, p), _)) = recv(&socks[0]) else {
+                panic!("no Phase2a");
+            };
+            assert_eq!((p.slot, p.ballot), (prop.slot, prop.ballot));
+        }
+        match reports.next() {
+            Agent::TimedOut(b, Some(p)) => assert_eq!((b, p.slot), (prop.ballot, prop.slot)),
+            a => panic!("expected a timeout, got {a:?}"),
+        }
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn commander_decides_with_a_majority() {
+        let socks = acceptors(3);
+        let mut reports = Reports::new();
+        let replica = UdpSocket::bind("127.0.0.1:0").unwrap();
+        replica.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let addr = replica.local_addr().unwrap();
+        let (rep, _) = reports.handler.network().connect(Transport::Udp, addr).unwrap();
+        let prop = proposal(7, Ballot::new(0, 1, NodeId::new()));
+        let agent = commander(&socks, Arc::new(vec![rep]), prop.clone(), reports.handler.clone());
+
+        for s in &socks[..2] {
+            let (_, from) = recv(s).unwrap();
+            let ack = Message::<String>::Phase2b(prop.ballot.leader_id, NodeId::new(), prop.ballot);
+            s.send_to(&to_vec(&ack).unwrap(), from).unwrap();
+        }
+        assert!(matches!(reports.next(), Agent::Committed));
+        let Some((Message::Decision(slot, _), _)) = recv(&replica) else {
+            panic!("replica never heard");
+        };
+        assert_eq!(slot, 7);
+        agent.join().unwrap();
+    }
+
+    #[test]
+    fn commander_gives_way_to_a_higher_ballot() {
+        let socks = acceptors(3);
+        let mut reports = Reports::new();
+        let prop = proposal(7, Ballot::new(0, 1, NodeId::new()));
+        let agent = commander(&socks, Arc::new(vec![]), prop.clone(), reports.handler.clone());
+
+        let higher = Ballot::new(0, 2, NodeId::new());
+        let (_, from) = recv(&socks[1]).unwrap();
+        let nack = Message::<String>::Phase2b(prop.ballot.leader_id, NodeId::new(), higher);
+        socks[1].send_to(&to_vec(&nack).unwrap(), from).unwrap();
+        match reports.next() {
+            Agent::Preempted(b) => assert_eq!(b, higher),
+            a => panic!("expected preemption, got {a:?}"),
+        }
+        agent.join().unwrap();
+    }
+
+    /// A leader at `addr` that has been up long enough to know who else is around.
+    fn leader(id: NodeId, handler: NodeHandler<Agent<String>>) -> Leader<String> {
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let db = Connection::open(":memory:").unwrap();
+        let mut leader = Leader::with_conn(id, handler, db, addr);
+        leader.membership.leaders = vec![addr];
+        leader.since = Instant::now() - leader.config.leader_timeout;
+        leader
+    }
+
+    #[test]
+    fn only_the_highest_live_leader_is_distinguished() {
+        let reports = Reports::new();
+        let mut ids = [NodeId::new(), NodeId::new(), NodeId::new()];
+        ids.sort();
+        let [low, mid, high] = ids;
+        let mut leader = leader(mid, reports.handler.clone());
+        leader.seen.insert(low, Instant::now());
+        assert!(leader.distinguished());
+
+        leader.seen.insert(high, Instant::now());
+        assert!(!leader.distinguished());
+        // Gone quiet.
+        leader.seen.insert(high, Instant::now() - leader.config.leader_timeout);
+        assert!(leader.distinguished());
+
+        // Not before we've had a chance to hear from everyone, and never outside the config.
+        leader.since = Instant::now();
+        assert!(!leader.distinguished());
+        leader.since = Instant::now() - leader.config.leader_timeout;
+        leader.membership.leaders.clear();
+        assert!(!leader.distinguished());
+    }
+
+    /// Leaders outside the config, whether they never joined or were removed since,
+    /// neither send heartbeats nor get counted for theirs.
+    #[test]
+    fn only_leaders_in_the_config_count() {
+        let reports = Reports::new();
+        let mut ids = [NodeId::new(), NodeId::new()];
+        ids.sort();
+        let [low, high] = ids;
+        let mut leader = leader(low, reports.handler.clone());
+        let (ours, other) = (leader.addr, "127.0.0.1:2".parse().unwrap());
+        leader.heard_from(high, other);
+        assert!(leader.distinguished());
+        leader.membership.leaders.push(other);
+        leader.heard_from(high, other);
+        assert!(!leader.distinguished());
+
+        // Reconfigured without it. Still running, but it no longer holds us up.
+        let next = Config { epoch: 1, leaders: vec![ours], acceptors: vec![] };
+        leader.next = Some((0, next));
+        assert!(leader.switch().is_some());
+        leader.since = Instant::now() - leader.config.leader_timeout;
+        leader.heard_from(high, other);
+        assert!(leader.distinguished());
+
+        let sock = &acceptors(1)[0];
+        let to = sock.local_addr().unwrap();
+        let (ep, _) = reports.handler.network().connect_sync(Transport::Udp, to).unwrap();
+        leader.heartbeat(&[ep]);
+        let Some((Message::Heartbeat(id, addr), _)) = recv(sock) else { panic!() };
+        assert_eq!((id, addr), (low, ours));
+        leader.membership.leaders.clear();
+        leader.heartbeat(&[ep]);
+        assert!(recv(sock).is_none());
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max() {
+        let mut reports = Reports::new();
+        let mut leader = leader(NodeId::new(), reports.handler.clone());
+        leader.config.backoff_min = Duration::from_millis(1);
+        leader.config.backoff_max = Duration::from_millis(8);
+        leader.backoff = leader.config.backoff_min;
+        for max in [2, 4, 8, 8] {
+            leader.back_off();
+            assert_eq!(leader.backoff, Duration::from_millis(max));
+        }
+        // Each one is tagged, so that only the latest wake-up counts.
+        let wakes = (0..4)
+            .map(|_| match reports.next() {
+                Agent::Wake(w) => w,
+                a => panic!("expected a wake-up, got {a:?}"),
+            })
+            .collect::<HashSet<_>>();
+        assert_eq!(wakes, HashSet::from([1, 2, 3, 4]));
+        assert_eq!(leader.wake, 4);
+    }
+
+    #[test]
+    fn holes_below_the_top_get_noops() {
+        let reports = Reports::new();
+        let mut leader = leader(NodeId::new(), reports.handler.clone());
+        leader.low = 2;
+        leader.ballot = Ballot::new(0, 3, leader.id);
+        let old = Ballot::new(0, 1, NodeId::new());
+        let mut pvals = HashMap::new();
+        pvals.insert(3, vec![proposal(3, old)]);
+        pvals.insert(6, vec![proposal(6, old), proposal(6, Ballot::new(0, 2, NodeId::new()))]);
+        leader.update(get_pmax(&pvals));
+        leader.fill_holes();
+
+        let mut slots = leader.proposals.keys().copied().collect::<Vec<_>>();
+        slots.sort();
+        // Nothing below the checkpoint, nothing past the top.
+        assert_eq!(slots, vec![2, 3, 4, 5, 6]);
+        for s in [2, 4, 5] {
+            assert_eq!(leader.proposals[&s].command, Decree::Noop);
+            assert_eq!(leader.proposals[&s].ballot, leader.ballot);
+        }
+        assert_eq!(leader.proposals[&6].ballot.num, 2);
+        assert!(matches!(leader.proposals[&3].command, Decree::Op(_)));
+    }
+
+    #[test]
+    fn checkpoint_waits_for_every_replica() {
+        let reports = Reports::new();
+        let mut leader = leader(NodeId::new(), reports.handler.clone());
+        for slot in 0..10 {
+            leader.proposals.insert(slot, proposal(slot, leader.ballot));
+        }
+        let reps = [NodeId::new(), NodeId::new(), NodeId::new()];
+        assert_eq!(leader.executed(reps[0], 8, 3), None);
+        assert_eq!(leader.executed(reps[1], 5, 3), None);
+        // The slowest one decides. Reports going backwards don't count.
+        assert_eq!(leader.executed(reps[2], 6, 3), Some(5));
+        assert_eq!(leader.executed(reps[1], 4, 3), None);
+        assert_eq!(leader.low, 5);
+        assert!(leader.proposals.keys().all(|s| *s >= 5));
+        assert_eq!(leader.next_slot(), 10);
+
+        assert_eq!(leader.executed(reps[1], 9, 3), Some(6));
+        // Nothing new past the checkpoint.
+        assert_eq!(leader.executed(reps[0], 9, 3), None);
+    }
+
+    /// A leader in both the old and the new config that hasn't heard of the new one yet
+    /// leaves its slots alone.
+    #[test]
+    fn slots_of_the_next_epoch_wait_for_the_switch() {
+        let reports = Reports::new();
+        let mut leader = leader(NodeId::new(), reports.handler.clone());
+        leader.active = true;
+        leader.ballot = Ballot::new(0, 2, leader.id);
+        let now = proposal(10, leader.ballot);
+        let later = proposal(40, Ballot::new(1, 0, leader.id));
+        assert!(leader.in_charge(&now));
+        assert!(!leader.in_charge(&later));
+
+        leader.proposals.insert(10, now);
+        leader.proposals.insert(40, later.clone());
+        leader.fill_holes();
+        // Only up to the top of our own epoch.
+        assert_eq!(leader.proposals.len(), 12);
+        assert!(!leader.proposals.contains_key(&39));
+
+        let next = Config { epoch: 1, ..leader.membership.clone() };
+        leader.configure(32, next);
+        assert!(leader.switch().is_none());
+        // One replica got past the start, the others may be anywhere.
+        let reps = [NodeId::new(), NodeId::new(), NodeId::new()];
+        assert_eq!(leader.executed(reps[0], 33, 3), None);
+        assert_eq!(leader.switch().map(|c| c.epoch), Some(1));
+        assert_eq!((leader.start, leader.low), (32, 0));
+        assert_eq!(leader.proposals.keys().copied().collect::<Vec<_>>(), vec![40]);
+
+        leader.active = true;
+        assert!(leader.in_charge(&later));
+        // No no-ops for what the old config decided.
+        leader.proposals.insert(34, proposal(34, leader.ballot));
+        leader.fill_holes();
+        assert!(!leader.proposals.contains_key(&31));
+        assert!(leader.proposals.contains_key(&32));
+    }
+
+    #[test]
+    fn leases_last_no_longer_than_acceptors_hold_them() {
+        let reports = Reports::new();
+        let mut leader = leader(NodeId::new(), reports.handler.clone());
+        leader.active = true;
+        leader.config.lease = Some(acceptor::MAX_LEASE * 5);
+        leader.lease_round = 1;
+        leader.lease_granted(NodeId::new(), 1, 3);
+        assert!(!leader.holds_lease());
+        leader.lease_granted(NodeId::new(), 1, 3);
+        assert!(leader.holds_lease());
+        let until = leader.lease_until.unwrap();
+        assert_eq!(until, leader.lease_sent + acceptor::MAX_LEASE - leader.config.drift);
+    }
+
+    /// A scout thread over `socks`, and the handle to send it `ScoutSignal`s.
+    fn scout(
+        socks: &[UdpSocket],
+        lid: NodeId,
+        oh: NodeHandler<Agent<String>>,
+    ) -> NodeHandler<ScoutSignal> {
+        let (h, l) = node::split::<ScoutSignal>();
+        let accs = socks
+            .iter()
+            .map(|s| h.network().connect(Transport::Udp, s.local_addr().unwrap()).unwrap().0)
+            .collect::<Vec<_>>();
+        let sh = h.clone();
+        thread::spawn(move || Agent::init_scout(lid, Arc::new(accs), l, sh, oh, quick()));
+        h
+    }
+
+    #[test]
+    fn scout_times_out_and_can_try_again() {
+        let socks = acceptors(3);
+        let mut reports = Reports::new();
+        let lid = NodeId::new();
+        let sh = scout(&socks, lid, reports.handler.clone());
+
+        let b1 = Ballot::new(0, 1, lid);
+        sh.signals().send(ScoutSignal::Scout(b1, 0));
+        assert!(matches!(recv(&socks[2]), Some((Message::Phase1a(_, b, 0), _)) if b == b1));
+        match reports.next() {
+            Agent::TimedOut(b, None) => assert_eq!(b, b1),
+            a => panic!("expected a timeout, got {a:?}"),
+        }
+
+        // Next attempt, and this time two of them answer. Late answers to the old one don't count.
+        let b2 = Ballot::new(0, 2, lid);
+        sh.signals().send(ScoutSignal::Scout(b2, 4));
+        for s in &socks[..2] {
+            let (b, from) = loop {
+                match recv(s).unwrap() {
+                    (Message::Phase1a(_, b, _), from) if b == b2 => break (b, from),
+                    _ => {}
+                }
+            };
+            let accepted = vec![proposal(5, Ballot::new(0, 1, NodeId::new()))];
+            let old = Message::<String>::Phase1b(lid, NodeId::new(), b1, vec![], None);
+            s.send_to(&to_vec(&old).unwrap(), from).unwrap();
+            let promise = Message::<String>::Phase1b(lid, NodeId::new(), b, accepted, None);
+            s.send_to(&to_vec(&promise).unwrap(), from).unwrap();
+        }
+        match reports.next() {
+            Agent::Adopted(b, pvals) => {
+                assert_eq!(b, b2);
+                assert_eq!(pvals[&5].len(), 2);
+            }
+            a => panic!("expected adoption, got {a:?}"),
+        }
+        sh.stop();
+    }
+
+    #[test]
+    fn scout_asks_for_the_rest_of_a_promise() {
+        let socks = acceptors(3);
+        let mut reports = Reports::new();
+        let lid = NodeId::new();
+        let sh = scout(&socks, lid, reports.handler.clone());
+
+        let b = Ballot::new(0, 1, lid);
+        sh.signals().send(ScoutSignal::Scout(b, 0));
+        for s in &socks[..2] {
+            let ask = |at| loop {
+                match recv(s).unwrap() {
+                    (Message::Phase1a(_, _, from), addr) if from == at => break addr,
+                    _ => {}
+                }
+            };
+            let from = ask(0);
+            let first = vec![proposal(5, Ballot::new(0, 0, NodeId::new()))];
+            let page = Message::<String>::Phase1b(lid, NodeId::new(), b, first, Some(7));
+            s.send_to(&to_vec(&page).unwrap(), from).unwrap();
+            ask(7);
+            let rest = vec![proposal(8, Ballot::new(0, 0, NodeId::new()))];
+            let page = Message::<String>::Phase1b(lid, NodeId::new(), b, rest, None);
+            s.send_to(&to_vec(&page).unwrap(), from).unwrap();
+        }
+        match reports.next() {
+            Agent::Adopted(_, pvals) => {
+                assert_eq!(pvals[&5].len(), 2);
+                assert_eq!(pvals[&8].len(), 2);
+            }
+            a => panic!("expected adoption, got {a:?}"),
+        }
+        sh.stop();
+    }
+}
@@ -1,4 +1,6 @@
-//! Achieve consensus on a sequence of Strings using the Paxos algorithm.
+//! Achieve consensus on a sequence of operations using the Paxos algorithm.
+//!
+//! Operations are [`KvOp`]s unless a different [`StateMachine`](crate::StateMachine) is plugged in.
 
 pub mod acceptor;
 pub mod dir;
@@ -6,45 +8,78 @@ pub mod leader;
 pub mod replica;
 
 
+use std::{net::SocketAddr, time::Duration};
+
 use serde_derive::{Deserialize, Serialize};
 
-use crate::{Entry, Identity, NodeId};
+use crate::{kv::KvOp, Entry, Identity, NodeId, Operation};
 
+/// Scoped to a configuration: acceptors keep a separate promise per epoch,
+/// so ballots from an old and a new config never compete for the same quorum.
+/// Field order matters, it's what the derived Ord goes by.
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
 pub struct Ballot {
+    pub epoch: usize,
     pub num: usize,
     pub leader_id: NodeId,
 }
 
 impl Ballot {
-    pub fn new(num: usize, leader_id: NodeId) -> Ballot {
-        Ballot { num, leader_id }
+    pub fn new(epoch: usize, num: usize, leader_id: NodeId) -> Ballot {
+        Ballot {
+            epoch,
+            num,
+            leader_id,
+        }
     }
 }
 
+/// Leaders and acceptors in charge of a range of slots.
+///
+/// A config decided at slot `s` runs slots from `s + WINDOW` on, the replicas' proposal window.
+/// Anything already proposed by then is still decided by the old one.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Config {
+    /// Filled in by the replicas when the config gets decided. One more than the last one.
+    pub epoch: usize,
+    pub leaders: Vec<SocketAddr>,
+    pub acceptors: Vec<SocketAddr>,
+}
+
+/// What goes in a slot.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub enum Decree<O = KvOp> {
+    /// Plugs a hole. Nothing happens.
+    Noop,
+    Op(Command<O>),
+    Reconfig(Config),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
-pub struct Command {
+pub struct Command<O = KvOp> {
     pub client_id: usize,
+    /// Picked at random every time the client starts. Op ids count from 0 within it.
+    pub session: u64,
     pub op_id: usize,
-    pub op: String, // Small
+    pub op: O, // Small
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Proposal {
+pub struct Proposal<O = KvOp> {
     pub slot: usize,
     pub ballot: Ballot,
-    pub command: Command,
+    pub command: Decree<O>,
 }
 
-impl PartialEq for Proposal {
+impl<O> PartialEq for Proposal<O> {
     fn eq(&self, other: &Self) -> bool {
         self.slot == other.slot && self.ballot == other.ballot
     }
 }
 
-impl Eq for Proposal {}
+impl<O> Eq for Proposal<O> {}
 
-impl PartialOrd for Proposal {
+impl<O> PartialOrd for Proposal<O> {
     fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
         if self.slot == other.slot {
             self.ballot.partial_cmp(&other.ballot)
@@ -54,7 +89,7 @@ impl PartialOrd for Proposal {
     }
 }
 
-impl Ord for Proposal {
+impl<O> Ord for Proposal<O> {
     fn cmp(&self, other: &Self) -> std::cmp::Ordering {
         if let Some(c) = self.partial_cmp(other) {
             c
@@ -66,20 +101,47 @@ impl Ord for Proposal {
 
 /// TODO: Replace usize with NodeID wherever necessary.
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Message {
+#[serde(bound = "")] // Operation already asks for everything serde needs.
+pub enum Message<O: Operation = KvOp> {
     // client <-> replica
-    Request(Command),
-    Response(usize, String, Result<String, String>), // Does the content matter?
+    Request(Command<O>),
+    Response(usize, O::Output), // op id, whatever the state machine said.
+
+    // admin -> replica
+    Reconfigure(Config), // Epoch gets filled in on the way.
 
     // replica <-> leader
-    Propose(usize, Command),
-    Decision(usize, Command),
+    Propose(usize, usize, Decree<O>), // Epoch of the config that runs the slot, slot.
+    Decision(usize, Decree<O>),
+    Read(Command<O>),
+    Configure(usize, Config), // In charge from this slot on.
+
+    // replica <-> replica
+    CatchUp(usize),                      // Send me what you decided from this slot on.
+    Decisions(Vec<(usize, Decree<O>)>),  // Slot, decision.
+    Snapshot(usize, usize, usize, Vec<u8>), // Everything below slot, applied. Offset, total, bytes.
+
+    // replica -> leader -> acceptor, replica
+    Executed(NodeId, usize), // replica id, slot_out
+    Checkpoint(usize),       // Every replica is past this slot. Forget what's below.
+
+    // leader -> replica
+    /// Answer to `Read`. Some(slot): serve it once everything below slot is applied.
+    /// None: no lease, put it through the log.
+    ReadIndex(Option<usize>, Command<O>),
 
     // leader <-> acceptor
-    Phase1a(NodeId, Ballot),                        // acceptor id
-    Phase1b(NodeId, NodeId, Ballot, Vec<Proposal>), // leader id, acceptor id,
-    Phase2a(NodeId, Proposal),                      // leader id
+    Phase1a(NodeId, Ballot, usize),                    // acceptor id, only pvalues from this slot on
+    /// Leader id, acceptor id, ballot, accepts. As many as fit in a datagram: if there's more,
+    /// the slot to ask again from.
+    Phase1b(NodeId, NodeId, Ballot, Vec<Proposal<O>>, Option<usize>),
+    Phase2a(NodeId, Proposal<O>),                      // leader id
     Phase2b(NodeId, NodeId, Ballot),                // leader id, acceptor id
+    Lease(NodeId, Ballot, usize, Duration),            // leader id, round, length
+    LeaseGrant(NodeId, NodeId, Ballot, usize),         // leader id, acceptor id, round
+
+    // leader <-> leader
+    Heartbeat(NodeId, SocketAddr), // leader id, address. Only leaders in the config count.
 
     /// Special
     /// Each time a new node joins the cluster, it sends this message to all other nodes.
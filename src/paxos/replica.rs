@@ -1,51 +1,61 @@
 #![allow(dead_code)]
-use crate::{Params, ReplicaState};
+use crate::{
+    session::Sessions, Operation, Params, StateMachine, HEADROOM, MAX_DATAGRAM, SNAPSHOT_CHUNK,
+};
 
-use self::dir::{get_all_leaders, remember_node, teach};
+use self::dir::{connect_all, get_all_replicas, initial_config, remember_node, teach};
 use hashbrown::HashMap;
 use message_io::{
     network::{Endpoint, NetEvent},
-    node::{NodeHandler, NodeListener},
+    node::{NodeEvent, NodeHandler, NodeListener},
 };
 use serde_json::{from_slice, to_vec};
 use sqlite::Connection; // Might have to change this to bincode or a custom impl.
-use std::{collections::BTreeMap, net::SocketAddr};
+use std::{collections::BTreeMap, net::SocketAddr, time::Duration};
 
 use super::*;
 
+/// Proposals in flight past `slot_out`. Also how long a decided config waits before taking over.
 const WINDOW: usize = 32;
-
-/// This can be something as simple as
-/// ```
-/// |q: ReplicaState| (q, Ok(""))
-/// ```
-/// in which case we'd be storing constants and not operations.
-///
-/// The triv() function does just that.
-pub struct Op {
-    /// Change to SocketAddr?
-    client_id: usize,
-    /// Sequence number.
-    op_id: usize,
-    /// The operation to be performed.
-    op: Box<dyn Fn(&ReplicaState) -> (ReplicaState, Result<String, String>) + Send + Sync>,
-}
+/// How often a replica checks whether it's stuck behind a missing decision.
+const CATCHUP_INTERVAL: Duration = Duration::from_millis(200);
+/// Most decisions sent in one go. Fewer if they wouldn't fit in a datagram.
+const CATCHUP_BATCH: usize = 64;
+/// Further behind than this, and a snapshot is cheaper than the decisions.
+const CATCHUP_LIMIT: usize = 1024;
 
 /// Node struct.
-pub struct Replica {
+pub struct Replica<S: StateMachine> {
     /// Just a lil number. Unique among all replicas.
     id: NodeId,
-    /// Eh, just some state.
-    state: ReplicaState,
+    /// The replicated state. Every decided command is applied to this, in slot order.
+    /// Wrapped in a session table, so each (client, op) runs once.
+    state: Sessions<(usize, u64), S>,
     /// Things for the algorithm.
     slot_in: usize,
     slot_out: usize,
-    /// Outstanding requests from clients
-    requests: Vec<Command>,
+    /// Outstanding requests from clients, and reconfigurations from admins.
+    requests: Vec<Decree<S::Op>>,
     /// Outstaning proposals that have been sent out, but not decided upon.
-    proposals: BTreeMap<usize, Command>,
+    proposals: BTreeMap<usize, Decree<S::Op>>,
     /// These are the done deals.
-    decisions: HashMap<usize, Command>,
+    decisions: HashMap<usize, Decree<S::Op>>,
+    /// Configs by the first slot they run, with their leaders connected.
+    /// The one in force at `slot_out` and any decided after it.
+    configs: BTreeMap<usize, (Config, Vec<Endpoint>)>,
+    /// Whether the leaders hold leases. Otherwise reads just go through the log.
+    lease_reads: bool,
+    /// Reads sent to the leaders, waiting to hear whether they can skip the log,
+    /// with the catch-up check they came in after.
+    reads: Vec<(usize, Command<S::Op>)>,
+    /// Reads cleared by a lease, served once `slot_out` gets to the slot.
+    leased: Vec<(usize, Command<S::Op>)>,
+    /// `slot_out` at the last catch-up check.
+    stalled: usize,
+    /// Pieces of the snapshot we're getting, by offset, and the slot it's for.
+    incoming: (usize, BTreeMap<usize, Vec<u8>>),
+    /// Catch-up checks so far.
+    ticks: usize,
 
     /// These are the guys you gotta talk to.
     // leaders: Vec<Endpoint>,
@@ -61,16 +71,23 @@ pub struct Replica {
     db: Connection,
 }
 
-impl Replica {
+impl<S: StateMachine> Replica<S> {
     pub fn new(id: NodeId, addr: SocketAddr, handler: NodeHandler<()>) -> Self {
         Self {
             id,
-            state: ReplicaState::default(),
+            state: Sessions::default(),
             slot_in: 0,
             slot_out: 0,
             requests: vec![],
             proposals: BTreeMap::new(),
             decisions: HashMap::new(),
+            configs: BTreeMap::new(),
+            lease_reads: false,
+            reads: vec![],
+            leased: vec![],
+            stalled: 0,
+            incoming: (0, BTreeMap::new()),
+            ticks: 0,
             handler,
             clients: HashMap::new(),
             addr,
@@ -81,12 +98,19 @@ impl Replica {
     pub fn with_conn(id: NodeId, addr: SocketAddr, handler: NodeHandler<()>, db: Connection) -> Self {
         Self {
             id,
-            state: ReplicaState::default(),
+            state: Sessions::default(),
             slot_in: 0,
             slot_out: 0,
             requests: vec![],
             proposals: BTreeMap::new(),
             decisions: HashMap::new(),
+            configs: BTreeMap::new(),
+            lease_reads: false,
+            reads: vec![],
+            leased: vec![],
+            stalled: 0,
+            incoming: (0, BTreeMap::new()),
+            ticks: 0,
             handler,
             clients: HashMap::new(),
             addr,
@@ -94,16 +118,35 @@ impl Replica {
         }
     }
 
+    /// The config that runs `slot`, and its leaders.
+    fn config_at(&self, slot: usize) -> &(Config, Vec<Endpoint>) {
+        self.configs.range(..=slot).next_back().unwrap().1
+    }
+
+    /// Leaders of the config that runs `slot`.
+    fn leaders_at(&self, slot: usize) -> &[Endpoint] {
+        &self.config_at(slot).1
+    }
+
+    /// Leaders of every config we know of. The old ones still need to hear how far we got.
+    fn all_leaders(&self) -> impl Iterator<Item = &Endpoint> {
+        self.configs.values().flat_map(|(_, eps)| eps.iter())
+    }
+
     /// Self explanatory name.
     ///
-    /// Each proposal is removed from `requests`, topped off with a slot, and sent to all leaders.
+    /// Each proposal is removed from `requests`, topped off with a slot, and sent to all leaders
+    /// in charge of that slot. It says which epoch the slot is in, so that leaders who haven't
+    /// heard of a new config yet don't decide it under the old one.
     /// This is done for multiple requests, each getting a different slot.
-    fn propose(&mut self, leaders: &Vec<Endpoint>) {
+    fn propose(&mut self) {
         while self.slot_in < self.slot_out + WINDOW && !self.requests.is_empty() {
             if self.decisions.get(&self.slot_in).is_none() {
+                let (config, leaders) = self.config_at(self.slot_in);
+                let (epoch, leaders) = (config.epoch, leaders.clone());
                 let c = self.requests.pop().unwrap(); // do this
                 self.proposals.insert(self.slot_in, c.clone()); // and then do that
-                let msg = Message::Propose(self.slot_in, c); // And the this.
+                let msg = Message::Propose(epoch, self.slot_in, c); // And the this.
                 let buf = to_vec(&msg).unwrap();
 
                 // Now send the bloody thing
@@ -119,7 +162,7 @@ impl Replica {
     /// Simple pipeline.
     /// Gets thing from leader, sends thing to client.
     /// Shimpul.
-    fn perform(&mut self, op: Command) {
+    fn perform(&mut self, op: Command<S::Op>) {
         /*
             NOTE:
             - Pseudocode has this particular if block so as to avoid duplicate executions in case one command is decided at multiple slots.
@@ -127,6 +170,7 @@ impl Replica {
             - We contend that a command may mutate some external state, and hence is not idempotent.
             - Thus, this block has been commented out.
             - We *are* keeping this, just in case.
+            - The session table does this job now, and catches client retries too.
         */
 
         // if self.decisions.contains(&Some(op)) {
@@ -136,86 +180,450 @@ impl Replica {
 
         // dbg!(&self.clients, &op);
         let addr = self.clients.get(&op.client_id);
-        let (state, res) = ReplicaState::triv(op.op)(&self.state);
         // For some reason, this should be atomic, but since we're not using threads, it's fine.
-        {
+        let res = {
             // let _un = self.lock.lock().unwrap();
-            self.state = state;
+            let res = self.state.execute((op.client_id, op.session), op.op_id, &op.op);
             self.slot_out += 1;
-        }
+            res
+        };
         // dbg!("PERFORM");
 
+        // Too old to know what it returned. The client has long since moved on.
+        let Some(res) = res else {
+            return;
+        };
         if let Some(addr) = addr {
-            // TODO: Change the contents of Message::Response, maybe. Don't think String is enough.
-            let msg = Message::Response(op.op_id, "Hello there".to_string(), res);
+            let msg = Message::<S::Op>::Response(op.op_id, res);
 
             let buf = to_vec(&msg).unwrap();
             // self.sock.send_to(&buf, addr).unwrap();
             self.handler.network().send(*addr, &buf);
         }
     }
+
+    /// A config got decided at `slot_out`. It takes over `WINDOW` slots later, past anything
+    /// that could have been proposed to the old one already.
+    fn reconfigure(&mut self, mut config: Config) {
+        let last = self.configs.values().next_back().unwrap().0.epoch;
+        config.epoch = last + 1;
+        let start = self.slot_out + WINDOW;
+        let eps = connect_all(&self.handler, &config.leaders);
+        self.configs.insert(start, (config, eps));
+        self.slot_out += 1;
+        self.announce();
+    }
+
+    /// Tells old and new leaders about every config after the first.
+    /// Leaders that just joined need this to find out they're in.
+    fn announce(&self) {
+        for (start, (config, _)) in self.configs.iter() {
+            if config.epoch == 0 {
+                continue;
+            }
+            let buf = to_vec(&Message::<S::Op>::Configure(*start, config.clone())).unwrap();
+            for l in self.all_leaders() {
+                self.handler.network().send(*l, &buf);
+            }
+        }
+    }
+
+    /// Applies decisions for as long as there's one for `slot_out`.
+    fn advance(&mut self) {
+        while let Some(c1) = self.decisions.get(&self.slot_out) {
+            if let Some(c2) = self.proposals.remove(&self.slot_out) {
+                if c2 != *c1 {
+                    self.requests.push(c2);
+                }
+            }
+
+            // Actually do the thing.
+            match c1.clone() { // GAH, CLONES!
+                Decree::Op(c1) => self.perform(c1),
+                Decree::Noop => self.slot_out += 1,
+                Decree::Reconfig(c) => self.reconfigure(c),
+            }
+        }
+        // Configs that have been taken over from are done with.
+        let current = *self.configs.range(..=self.slot_out).next_back().unwrap().0;
+        self.configs = self.configs.split_off(&current);
+        self.serve_reads();
+    }
+
+    /// Runs every `CATCHUP_INTERVAL`. If we're `stuck`, ask the other replicas.
+    ///
+    /// Also tells the leaders how far we got, so they can work out a checkpoint,
+    /// and which configs are coming up. Reads that have waited a whole interval for the leaders
+    /// to answer go through the log instead, in case the `Read` or the answer got lost.
+    fn catch_up(&mut self, peers: &[Endpoint]) {
+        self.ticks += 1;
+        let ticks = self.ticks;
+        let (late, rest) = std::mem::take(&mut self.reads)
+            .into_iter()
+            .partition::<Vec<_>, _>(|(t, _)| *t + 1 < ticks);
+        self.reads = rest;
+        self.requests.extend(late.into_iter().map(|(_, c)| Decree::Op(c)));
+
+        let buf = to_vec(&Message::<S::Op>::Executed(self.id, self.slot_out)).unwrap();
+        for l in self.all_leaders() {
+            self.handler.network().send(*l, &buf);
+        }
+        self.announce();
+
+        if self.stuck() {
+            let buf = to_vec(&Message::<S::Op>::CatchUp(self.slot_out)).unwrap();
+            for p in peers.iter() {
+                self.handler.network().send(*p, &buf);
+            }
+        }
+        self.stalled = self.slot_out;
+        self.handler.signals().send_with_timer((), CATCHUP_INTERVAL);
+    }
+
+    /// Nothing got applied since the last check, and something is waiting on it: decisions past
+    /// a hole, our own proposals, or leased reads. Those decisions may well have been made, and
+    /// only their datagrams lost. With no traffic after them, nothing else would tell us.
+    fn stuck(&self) -> bool {
+        let gap = self.decisions.keys().any(|s| *s > self.slot_out);
+        let waiting = gap || !self.proposals.is_empty() || !self.leased.is_empty();
+        waiting && self.stalled == self.slot_out
+    }
+
+    /// Someone is stuck at `from`. Send what we have applied since, or the whole state if that's a lot
+    /// or we no longer have the decisions (we got here by snapshot ourselves).
+    ///
+    /// The state goes out in `SNAPSHOT_CHUNK`s, all at once. Whatever gets lost is sent again
+    /// the next time they ask.
+    fn help(&self, ep: Endpoint, from: usize) {
+        if from >= self.slot_out {
+            return;
+        }
+        let end = self.slot_out.min(from + CATCHUP_BATCH);
+        let have = (from..end).all(|s| self.decisions.contains_key(&s));
+        if self.slot_out - from > CATCHUP_LIMIT || !have {
+            let snapshot = self.snapshot();
+            for (i, chunk) in snapshot.chunks(SNAPSHOT_CHUNK).enumerate() {
+                let msg = Message::<S::Op>::Snapshot(
+                    self.slot_out,
+                    i * SNAPSHOT_CHUNK,
+                    snapshot.len(),
+                    chunk.to_vec(),
+                );
+                self.handler.network().send(ep, &to_vec(&msg).unwrap());
+            }
+            return;
+        }
+        let mut ds = vec![];
+        let mut bytes = HEADROOM;
+        for s in from..end {
+            let d = (s, self.decisions[&s].clone());
+            bytes += to_vec(&d).unwrap().len();
+            if !ds.is_empty() && bytes > MAX_DATAGRAM {
+                break;
+            }
+            ds.push(d);
+        }
+        let msg = Message::<S::Op>::Decisions(ds);
+        self.handler.network().send(ep, &to_vec(&msg).unwrap());
+    }
+
+    /// A piece of a peer's snapshot. Once we have all of them, installs it.
+    /// Pieces of an older one than we're collecting are no use.
+    fn receive_chunk(&mut self, slot: usize, offset: usize, total: usize, data: Vec<u8>) {
+        if slot <= self.slot_out || slot < self.incoming.0 {
+            return;
+        }
+        if slot > self.incoming.0 {
+            self.incoming = (slot, BTreeMap::new());
+        }
+        self.incoming.1.insert(offset, data);
+        if self.incoming.1.values().map(Vec::len).sum::<usize>() < total {
+            return;
+        }
+        let (_, chunks) = std::mem::take(&mut self.incoming);
+        let snapshot = chunks.into_values().flatten().collect::<Vec<_>>();
+        self.install(slot, &snapshot);
+    }
+
+    /// State and configs together. Configs are part of what the log decided.
+    fn snapshot(&self) -> Vec<u8> {
+        let configs = self
+            .configs
+            .iter()
+            .map(|(s, (c, _))| (*s, c))
+            .collect::<Vec<_>>();
+        to_vec(&(self.state.snapshot(), configs)).unwrap()
+    }
+
+    /// Jumps straight to `slot` with a peer's state.
+    fn install(&mut self, slot: usize, snapshot: &[u8]) {
+        if slot <= self.slot_out {
+            return;
+        }
+        let (state, configs): (Vec<u8>, Vec<(usize, Config)>) = from_slice(snapshot).unwrap();
+        self.state.restore(&state);
+        self.configs = configs
+            .into_iter()
+            .map(|(s, c)| {
+                let eps = connect_all(&self.handler, &c.leaders);
+                (s, (c, eps))
+            })
+            .collect();
+        self.slot_out = slot;
+        self.slot_in = self.slot_in.max(slot);
+        self.decisions.retain(|s, _| *s >= slot);
+        // No telling which of these made it. Try again, the session table weeds out repeats.
+        let old = self.proposals.split_off(&slot);
+        let lost = std::mem::replace(&mut self.proposals, old);
+        self.requests.extend(lost.into_values());
+    }
+
+    /// A client wants `c` done. Reads ask the leaders about a lease first, if they have any.
+    fn request(&mut self, c: Command<S::Op>) {
+        if !(self.lease_reads && c.op.is_read()) {
+            self.requests.push(Decree::Op(c));
+            return;
+        }
+        let buf = to_vec(&Message::Read(c.clone())).unwrap();
+        for l in self.leaders_at(self.slot_out) {
+            self.handler.network().send(*l, &buf);
+        }
+        self.reads.push((self.ticks, c));
+    }
+
+    /// Serves leased reads that the state has caught up with. No slot involved.
+    fn serve_reads(&mut self) {
+        let (ready, rest) = std::mem::take(&mut self.leased)
+            .into_iter()
+            .partition(|(slot, _)| *slot <= self.slot_out);
+        self.leased = rest;
+        for (_, op) in ready {
+            let res = self.state.state().apply(&op.op);
+            if let Some(addr) = self.clients.get(&op.client_id) {
+                let msg = Message::<S::Op>::Response(op.op_id, res);
+                self.handler.network().send(*addr, &to_vec(&msg).unwrap());
+            }
+        }
+    }
 }
 
 /// This is the main loop for the replica. It listens for messages from the leaders and clients.
-pub fn listen(id: NodeId, addr: SocketAddr, listener: NodeListener<()>, handler: NodeHandler<()>) {
-    let mut rep = Replica::new(id, addr, handler.clone());
-    let leaders = get_all_leaders(handler, &rep.db);
+///
+/// `S` is the state being replicated, e.g. `ReplicaState`.
+/// `lease_reads` should match whether the leaders were given a `lease`.
+pub fn listen<S: StateMachine>(
+    id: NodeId,
+    addr: SocketAddr,
+    listener: NodeListener<()>,
+    handler: NodeHandler<()>,
+    lease_reads: bool,
+) {
+    let mut rep = Replica::<S>::new(id, addr, handler.clone());
+    rep.lease_reads = lease_reads;
+    let config = initial_config(&rep.db);
+    let leaders = connect_all(&handler, &config.leaders);
+    rep.configs.insert(0, (config, leaders));
+    let peers = get_all_replicas(handler, &rep.db)
+        .into_iter()
+        .filter(|ep| ep.addr() != addr)
+        .collect::<Vec<_>>();
     let params = Params::new();
     // println!("Inited replica {id}.");
-    let mut nt = listener.for_each_async(move |event| match event.network() {
-        NetEvent::Message(endpoint, buf) => {
-            let msg = from_slice::<Message>(&buf).unwrap();
-            // dbg!(&msg);
-            match msg {
-                Message::Request(c) => {
-                    let c = c.clone();
-                    let _ = rep.clients.try_insert(c.client_id, endpoint);
-                    rep.requests.push(c);
-                    // dbg!(&rep.requests);
-                }
-                Message::Decision(slot, command) => {
-                    // Accept the consensus.
-                    rep.decisions.insert(slot, command);
-                    while let Some(c1) = rep.decisions.get(&rep.slot_out) {
-                        if let Some(c2) = rep.proposals.remove(&rep.slot_out) {
-                            if c2 != *c1 {
-                                rep.requests.push(c2);
+    rep.handler.signals().send_with_timer((), CATCHUP_INTERVAL);
+    let mut nt = listener.for_each_async(move |event| {
+        let net = match event {
+            NodeEvent::Network(net) => net,
+            NodeEvent::Signal(()) => {
+                rep.catch_up(&peers);
+                return;
+            }
+        };
+        match net {
+            NetEvent::Message(endpoint, buf) => {
+                let msg = from_slice::<Message<S::Op>>(&buf).unwrap();
+                // dbg!(&msg);
+                match msg {
+                    Message::Request(c) => {
+                        let c = c.clone();
+                        let _ = rep.clients.try_insert(c.client_id, endpoint);
+                        rep.request(c);
+                        // dbg!(&rep.requests);
+                    }
+                    Message::ReadIndex(index, c) => {
+                        // Several leaders may answer. First one wins.
+                        let Some(i) = rep.reads.iter().position(|(_, r)| *r == c) else {
+                            return;
+                        };
+                        let (_, c) = rep.reads.swap_remove(i);
+                        match index {
+                            Some(slot) => rep.leased.push((slot, c)),
+                            None => rep.requests.push(Decree::Op(c)),
+                        }
+                        rep.serve_reads();
+                    }
+                    Message::Decision(slot, command) => {
+                        // Accept the consensus.
+                        rep.decisions.insert(slot, command);
+                        rep.advance();
+                        // dbg!(&rep.decisions);
+                        // Counting decisions stopped working once old ones get dropped.
+                    if rep.slot_out >= params.k {
+                            // println!("{id} returning");
+                            rep.handler.stop();
+                            return; // Timing.
+                        }
+                    }
+                    Message::CatchUp(from) => rep.help(endpoint, from),
+                    Message::Decisions(ds) => {
+                        for (slot, command) in ds {
+                            if slot >= rep.slot_out {
+                                rep.decisions.insert(slot, command);
                             }
                         }
-
-                        // Actually do the thing.
-                        rep.perform(c1.clone()); // GAH, CLONES!
+                        rep.advance();
                     }
-                    // dbg!(&rep.decisions);
-                    if rep.decisions.len() >= params.k {
-                        // println!("{id} returning");
-                        rep.handler.stop();
-                        return; // Timing.
+                    Message::Snapshot(slot, offset, total, data) => {
+                        rep.receive_chunk(slot, offset, total, data);
+                        rep.advance();
                     }
-                }
-                Message::Identify(entry, reply) => {
-                    let Ok(_) = remember_node(&rep.db, &entry) else {
-                        panic!("WTF.");
-                    };
+                    Message::Reconfigure(config) => {
+                        rep.requests.push(Decree::Reconfig(config));
+                    }
+                    Message::Checkpoint(slot) => {
+                        // Everyone has these. Peers that fall behind get a snapshot instead.
+                        rep.decisions.retain(|s, _| *s >= slot);
+                    }
+                    Message::Identify(entry, reply) => {
+                        let Ok(_) = remember_node(&rep.db, &entry) else {
+                            panic!("WTF.");
+                        };
 
-                    if reply {
-                        teach(Entry { id: rep.id, kind: Identity::Replica, addr: rep.addr });
+                        if reply {
+                            teach(Entry { id: rep.id, kind: Identity::Replica, addr: rep.addr });
+                        }
                     }
+                    _ => unreachable!(), // It had better be, damn it.
                 }
-                _ => unreachable!(), // It had better be, damn it.
+                rep.propose();
+            }
+            NetEvent::Connected(_ep, _) => {
+                // println!("Replica {id} Connected to {ep}.");
+            }
+            NetEvent::Accepted(_ep, _) => {
+                // println!("Replica {id} Accepted {ep}.");
+            }
+            NetEvent::Disconnected(_ep) => {
+                // println!("Replica {id} Disconnected from {ep}.");
             }
-            rep.propose(&leaders);
         }
-        NetEvent::Connected(_ep, _) => {
-            // println!("Replica {id} Connected to {ep}.");
+    });
+
+    nt.wait();
+}
+
+#[cfg(test)]
+mod tests {
+    use message_io::node;
+
+    use super::*;
+    use crate::kv::{KvOp, KvStore};
+
+    fn replica(lease_reads: bool) -> Replica<KvStore> {
+        let (handler, _) = node::split::<()>();
+        let addr = "127.0.0.1:1".parse().unwrap();
+        let db = Connection::open(":memory:").unwrap();
+        let mut rep = Replica::with_conn(NodeId::new(), addr, handler, db);
+        rep.lease_reads = lease_reads;
+        rep.configs.insert(0, (Config::default(), vec![]));
+        rep
+    }
+
+    fn get(op_id: usize) -> Command<KvOp> {
+        Command { client_id: 0, session: 0, op_id, op: KvOp::Get { key: "k".into() } }
+    }
+
+    #[test]
+    fn snapshots_arrive_in_pieces() {
+        let mut a = replica(false);
+        for i in 0..2000 {
+            let put = KvOp::Put { key: format!("key{i}"), value: "x".repeat(20) };
+            a.state.execute((0, 0), i, &put);
         }
-        NetEvent::Accepted(_ep, _) => {
-            // println!("Replica {id} Accepted {ep}.");
+        a.slot_out = 2000;
+        let snapshot = a.snapshot();
+        let chunks = snapshot
+            .chunks(SNAPSHOT_CHUNK)
+            .enumerate()
+            .map(|(i, c)| (i * SNAPSHOT_CHUNK, c.to_vec()))
+            .collect::<Vec<_>>();
+        assert!(chunks.len() > 1);
+        for (offset, data) in chunks.iter().cloned() {
+            let msg = Message::<KvOp>::Snapshot(2000, offset, snapshot.len(), data);
+            assert!(to_vec(&msg).unwrap().len() <= MAX_DATAGRAM);
         }
-        NetEvent::Disconnected(_ep) => {
-            // println!("Replica {id} Disconnected from {ep}.");
+
+        let mut b = replica(false);
+        let total = snapshot.len();
+        // Some of an older one first. It gets dropped once a newer one shows up.
+        b.receive_chunk(1500, 0, total, vec![0; SNAPSHOT_CHUNK]);
+        // Out of order, and one of them twice.
+        for (offset, data) in chunks.iter().rev().skip(1).chain(chunks.iter().take(2)) {
+            b.receive_chunk(2000, *offset, total, data.clone());
         }
-    });
+        assert_eq!(b.slot_out, 0);
+        b.receive_chunk(1500, 0, total, vec![0; SNAPSHOT_CHUNK]);
+        let (offset, data) = chunks.last().unwrap().clone();
+        b.receive_chunk(2000, offset, total, data);
+        assert_eq!(b.slot_out, 2000);
+        assert_eq!(b.snapshot(), snapshot);
+        assert!(b.incoming.1.is_empty());
+    }
 
-    nt.wait();
+    /// The last decisions can get lost with nothing after them. Our own proposals still
+    /// waiting are enough to go ask, once a whole interval has gone by without progress.
+    #[test]
+    fn lost_last_decisions_are_asked_for() {
+        let mut rep = replica(false);
+        assert!(!rep.stuck());
+        rep.proposals.insert(0, Decree::Op(get(0)));
+        rep.stalled = 1;
+        assert!(!rep.stuck());
+        rep.stalled = 0;
+        assert!(rep.stuck());
+
+        rep.decisions.insert(0, Decree::Op(get(0)));
+        rep.advance();
+        assert!(rep.proposals.is_empty());
+        rep.catch_up(&[]);
+        assert!(!rep.stuck());
+        // A leased read waiting on a slot nothing else will bring.
+        rep.leased.push((3, get(1)));
+        assert!(rep.stuck());
+    }
+
+    #[test]
+    fn reads_go_through_the_log_without_leases() {
+        let mut rep = replica(false);
+        rep.request(get(0));
+        assert!(rep.reads.is_empty());
+        assert_eq!(rep.requests, vec![Decree::Op(get(0))]);
+    }
+
+    #[test]
+    fn unanswered_reads_fall_back_to_the_log() {
+        let mut rep = replica(true);
+        rep.request(get(0));
+        assert_eq!(rep.reads.len(), 1);
+        assert!(rep.requests.is_empty());
+
+        // Might have come in right before the check. Give the leaders a whole interval.
+        rep.catch_up(&[]);
+        rep.request(get(1));
+        assert_eq!(rep.reads.len(), 2);
+        rep.catch_up(&[]);
+        assert_eq!(rep.requests, vec![Decree::Op(get(0))]);
+        rep.catch_up(&[]);
+        assert_eq!(rep.requests, vec![Decree::Op(get(0)), Decree::Op(get(1))]);
+        assert!(rep.reads.is_empty());
+    }
 }
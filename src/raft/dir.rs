@@ -1,6 +1,6 @@
 #![allow(dead_code)]
 use std::{
-    net::SocketAddr,
+    path::Path,
     thread::{self, JoinHandle},
 };
 
@@ -10,24 +10,27 @@ use message_io::{
     node::NodeHandler,
 };
 
-use crate::LOOPBACK;
+use crate::StateMachine;
 
-use super::server;
+use super::{server, Config, RaftConfig};
 
 pub const RAFT_PORT: u16 = 9000;
 pub const RAFT_COUNT: usize = 5;
+/// Default home for each server's term, vote and log.
+pub const RAFT_DATA: &str = "raft-data";
 
-pub fn get_peers<Y>(id: usize, handler: NodeHandler<Y>) -> HashMap<usize, Endpoint> {
-    (0..RAFT_COUNT)
-        .filter(|&i| i != id)
-        .map(|i| {
-            let out = handler
-                .network()
-                .connect(
-                    Transport::Udp,
-                    SocketAddr::from((LOOPBACK, RAFT_PORT + i as u16)),
-                )
-                .unwrap();
+/// The cluster a brand new local deployment starts out as. Changes after that go through the log.
+pub fn initial_config() -> Config {
+    RaftConfig::default().initial()
+}
+
+/// Endpoints for everyone in `config` but `id`, learners included.
+pub fn get_peers<Y>(id: usize, config: &Config, handler: &NodeHandler<Y>) -> HashMap<usize, Endpoint> {
+    config
+        .servers()
+        .filter(|(&i, _)| i != id)
+        .map(|(&i, &addr)| {
+            let out = handler.network().connect(Transport::Udp, addr).unwrap();
             // dbg!(&out);
             (i, out.0)
         })
@@ -50,11 +53,13 @@ pub fn get_peers<Y>(id: usize, handler: NodeHandler<Y>) -> HashMap<usize, Endpoi
         .collect()
 }
  */
-pub fn raft_init() -> Vec<JoinHandle<()>> {
+/// One thread per server in `settings`, all replicating an `S`.
+pub fn raft_init<S: StateMachine>(settings: &RaftConfig) -> Vec<JoinHandle<()>> {
     let mut out = vec![];
-    for i in 0..RAFT_COUNT {
+    for &i in settings.servers.keys() {
+        let settings = settings.clone();
         out.push(thread::spawn(move || {
-            server::run(i, SocketAddr::from((LOOPBACK, RAFT_PORT + i as u16)));
+            server::run::<S>(i, Path::new(RAFT_DATA), &settings);
         }));
     }
 
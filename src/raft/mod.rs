@@ -1,49 +1,272 @@
 #![allow(dead_code)]
-use std::net::SocketAddr;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    env, fs, io,
+    net::SocketAddr,
+    path::Path,
+};
 
 use serde::{Deserialize, Serialize};
 
+use crate::{kv::KvOp, Operation, HEADROOM, LOOPBACK, MAX_DATAGRAM};
+
+use self::dir::{RAFT_COUNT, RAFT_PORT};
+
 // use crate::paxos::Command;
 
 // use self::server::{Campaign, Replicate};
 
 pub mod dir;
+pub mod multi;
 pub mod server;
+pub mod storage;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
-pub struct Command {
+pub struct Command<O = KvOp> {
     pub client: SocketAddr,
+    /// Picked at random every time the client starts. Op ids count from 0 within it.
+    pub session: u64,
     pub op_id: usize,
-    pub op: String, // Small
+    pub op: O, // Small
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
-pub struct Log {
+pub struct Log<O = KvOp> {
     term: usize,
-    command: Option<Command>,
+    command: Option<Command<O>>,
+    /// Membership from here on. Takes effect as soon as it's in the log, committed or not.
+    #[serde(default)]
+    config: Option<Config>,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize, PartialEq, Eq, Hash)]
+/// Who is in the cluster, and where to find them.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct Config {
+    pub members: BTreeMap<usize, SocketAddr>,
+    /// Get the log and apply it, but don't vote and don't count towards a majority.
+    #[serde(default)]
+    pub learners: BTreeMap<usize, SocketAddr>,
+}
+
+impl Config {
+    /// Servers needed for a majority.
+    pub fn quorum(&self) -> usize {
+        self.members.len() / 2 + 1
+    }
+
+    /// Member or learner.
+    pub fn contains(&self, id: usize) -> bool {
+        self.members.contains_key(&id) || self.learners.contains_key(&id)
+    }
+
+    /// Everyone who gets the log.
+    pub fn servers(&self) -> impl Iterator<Item = (&usize, &SocketAddr)> {
+        self.members.iter().chain(self.learners.iter())
+    }
+
+    pub fn apply(&mut self, change: &Change) {
+        match change {
+            Change::Add(id, addr) => {
+                self.learners.remove(id);
+                self.members.insert(*id, *addr);
+            }
+            Change::Learn(id, addr) => {
+                if !self.members.contains_key(id) {
+                    self.learners.insert(*id, *addr);
+                }
+            }
+            Change::Remove(id) => {
+                self.members.remove(id);
+                self.learners.remove(id);
+            }
+        }
+    }
+}
+
+/// How to run a cluster: where every server is, and how fast everything goes.
+///
+/// Loaded from a JSON file, e.g. `{"servers": {"0": "10.0.0.1:9000", ...}, "heartbeat": 20}`.
+/// Anything left out takes its default, which is the local five-server cluster.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct RaftConfig {
+    /// Every server there is, by id. Any host and port.
+    pub servers: BTreeMap<usize, SocketAddr>,
+    /// Who a brand new cluster starts out as. All of `servers` if not given.
+    /// Anyone else has to join with `raft_admin`.
+    pub members: Option<BTreeSet<usize>>,
+    /// Election timeouts are picked between these, in ms.
+    pub election_min: u64,
+    pub election_max: u64,
+    /// Time between heartbeats, in ms. Has to be well under `election_min`.
+    pub heartbeat: u64,
+    /// A batch unanswered for this many ms is taken as lost.
+    /// It goes again, with everything after it.
+    pub retransmit: u64,
+    /// Batches out to one follower at once, unanswered.
+    pub max_inflight: usize,
+    /// Most entries in one batch, and the most bytes. A batch always has at least one entry.
+    /// A request that makes for a bigger entry than `max_batch_bytes` is turned away.
+    pub max_batch: usize,
+    pub max_batch_bytes: usize,
+    /// Messages held while there's no leader to pass them on to.
+    /// Past this, clients get turned away.
+    pub pending_max: usize,
+    /// Snapshot and compact once this many applied entries pile up in the log.
+    pub snapshot_every: usize,
+    /// Serve reads off a lease instead of a heartbeat round per read. Saves a round trip,
+    /// but counts on clocks running at about the same rate everywhere.
+    pub lease_reads: bool,
+    /// Shaved off the end of every lease, in ms, for clocks that don't.
+    pub lease_drift: u64,
+}
+
+impl Default for RaftConfig {
+    fn default() -> Self {
+        Self {
+            servers: (0..RAFT_COUNT)
+                .map(|i| (i, SocketAddr::from((LOOPBACK, RAFT_PORT + i as u16))))
+                .collect(),
+            members: None,
+            election_min: 150,
+            election_max: 300,
+            heartbeat: 50,
+            retransmit: 100,
+            max_inflight: 64,
+            max_batch: 128,
+            // Under the smallest datagram there is (macOS), with room to spare for the rest.
+            max_batch_bytes: 8 * 1024,
+            pending_max: 1024,
+            snapshot_every: 1024,
+            lease_reads: false,
+            lease_drift: 30,
+        }
+    }
+}
+
+impl RaftConfig {
+    pub fn load(path: &Path) -> io::Result<Self> {
+        let out: Self = serde_json::from_slice(&fs::read(path)?)?;
+        let bad = |why: &str| Err(io::Error::new(io::ErrorKind::InvalidData, why.to_string()));
+        if out.election_min >= out.election_max {
+            return bad("election_min has to be under election_max");
+        }
+        if out.heartbeat >= out.election_min {
+            return bad("heartbeat has to be under election_min");
+        }
+        if out.lease_reads && out.lease_drift >= out.election_min {
+            return bad("lease_drift has to be under election_min");
+        }
+        if out.max_inflight == 0 || out.max_batch == 0 {
+            return bad("max_inflight and max_batch have to be at least 1");
+        }
+        if out.max_batch_bytes + HEADROOM > MAX_DATAGRAM {
+            let most = MAX_DATAGRAM - HEADROOM;
+            return bad(&format!("max_batch_bytes has to be at most {most}"));
+        }
+        if let Some(m) = out.members.iter().flatten().find(|m| !out.servers.contains_key(m)) {
+            return bad(&format!("member {m} isn't in servers"));
+        }
+        Ok(out)
+    }
+
+    /// The file after `--config` on the command line, or the defaults without one.
+    pub fn from_args() -> Self {
+        let args = env::args().collect::<Vec<_>>();
+        match args.iter().position(|a| a == "--config") {
+            Some(i) => Self::load(Path::new(&args[i + 1])).unwrap(),
+            None => Self::default(),
+        }
+    }
+
+    /// Where server `id` listens.
+    pub fn addr(&self, id: usize) -> SocketAddr {
+        match self.servers.get(&id) {
+            Some(addr) => *addr,
+            None => panic!("No address for server {id}."),
+        }
+    }
+
+    /// Membership of a brand new cluster.
+    pub fn initial(&self) -> Config {
+        let members = self.servers.iter().filter(|(i, _)| match &self.members {
+            Some(m) => m.contains(i),
+            None => true,
+        });
+        Config {
+            members: members.map(|(i, addr)| (*i, *addr)).collect(),
+            learners: BTreeMap::new(),
+        }
+    }
+}
+
+/// One server in or out at a time. Any two majorities of configs one change apart overlap,
+/// so there's no need for a joint phase.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, PartialEq, Eq)]
+pub enum Change {
+    /// Promotes a learner too.
+    Add(usize, SocketAddr),
+    /// In as a learner. Leaves majorities alone, so it can take its time catching up.
+    Learn(usize, SocketAddr),
+    Remove(usize),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize, PartialEq, Eq, Hash)]
 pub struct Reply {
     pub from: usize,
     pub success: bool,
     pub term: usize,
+    /// `round` of the heartbeat this answers. 0 for anything else.
+    #[serde(default)]
+    pub round: usize,
+    /// Heartbeats only, on success. Last entry known to match the leader's log.
+    #[serde(default)]
+    pub match_index: usize,
+    /// Heartbeats only, on a mismatch at `prev_log_index`. Term of the entry we have there
+    /// (None if the log is too short), and the first index we hold with that term
+    /// (or one past our last entry). The leader skips back over the whole term in one go.
+    #[serde(default)]
+    pub conflict_term: Option<usize>,
+    #[serde(default)]
+    pub conflict_index: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub enum Message {
-    Request(Command),
-    Response(Command),
-    Heartbeat(Replicate),
+#[serde(bound = "")] // Operation already asks for everything serde needs.
+pub enum Message<O: Operation = KvOp> {
+    Request(Command<O>),
+    Response(Command<O>, O::Output),
+    /// To a client, from a server that isn't the leader. With a `hint`, the request went on to
+    /// the leader there, and so should the next ones. Without, it was dropped: try again.
+    NotLeader {
+        cmd: Command<O>,
+        hint: Option<SocketAddr>,
+    },
+    /// To a client. The request is too big to ever go out in a batch, so it never goes in.
+    TooBig(Command<O>),
+    Heartbeat(Replicate<O>),
     Campaign(Campaign),
     ServerReply(Reply),
+    /// Would you vote for me? `term` is the one the candidate would run in. Nobody changes term over it.
+    PreVote(Campaign),
+    PreVoteReply(Reply),
+    InstallSnapshot(InstallSnapshot),
+    /// From an admin. Goes to the leader like a request. Dropped while another change is in flight.
+    Reconfigure(Change),
+    /// From an admin. Hand leadership over to this server. Routed like `Reconfigure`.
+    Transfer(usize),
+    /// Leader to the server it hands over to: your log is complete, campaign now.
+    /// Carries the leader's term.
+    TimeoutNow(usize),
 }
 
 #[derive(Debug, PartialEq, Eq, Clone, Copy)]
 enum ServerState {
     Follower,
+    PreCandidate(usize), // Same, but pre-votes. Still in the old term.
     Candidate(usize), // Contains number of votes
     Leader,
+    Learner, // Follows, never campaigns
 }
 
 #[derive(Debug, Copy, Clone, Serialize, Deserialize)]
@@ -53,12 +276,34 @@ pub struct Heartbeat {
     prev_log_index: usize,
     prev_log_term: usize,
     leader_commit: usize,
+    /// Counts the leader's broadcasts.
+    /// Any answer to one shows we were still leader when we sent it.
+    #[serde(default)]
+    round: usize,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
-pub struct Replicate {
+pub struct Replicate<O = KvOp> {
     hb: Heartbeat,
-    entries: Vec<(usize, Log)>,
+    entries: Vec<(usize, Log<O>)>,
+}
+
+/// Sent instead of entries when a follower needs some that were already compacted away.
+/// In pieces, since the whole thing won't fit in a datagram.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct InstallSnapshot {
+    term: usize,
+    leader_id: usize,
+    /// Last entry the snapshot covers, and its term.
+    last_index: usize,
+    last_term: usize,
+    /// Membership as of `last_index`.
+    config: Config,
+    /// Where `data` goes in the whole snapshot, and whether it's the last piece.
+    offset: usize,
+    done: bool,
+    /// A piece of whatever `StateMachine::snapshot` made of it, session table included.
+    data: Vec<u8>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -67,12 +312,64 @@ pub struct Campaign {
     candidate_id: usize,
     last_log_index: usize,
     last_log_term: usize,
+    /// Started by a `TimeoutNow`. Goes through even where the old leader is still heard from.
+    #[serde(default)]
+    transfer: bool,
 }
 
-#[derive(Debug, Clone, Serialize, Deserialize)]
+/// What a server's timers carry: its group (0 outside Multi-Raft), which timer went off,
+/// and that timer's generation. Only the latest generation of each timer counts.
+pub type Signal = (usize, Timer, usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum Timer {
     /// To send heartbeats. Contains prev_log_index
     Heartbeat,
     /// To initiate election
     Election,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load(name: &str, json: &str) -> io::Result<RaftConfig> {
+        let path = env::temp_dir().join(format!("raft-config-{}-{name}.json", std::process::id()));
+        fs::write(&path, json).unwrap();
+        let out = RaftConfig::load(&path);
+        fs::remove_file(path).unwrap();
+        out
+    }
+
+    #[test]
+    fn load_fills_in_the_rest() {
+        let json = r#"{"servers": {"3": "10.0.0.1:9000", "4": "10.0.0.2:9000"}, "members": [3],
+            "heartbeat": 20}"#;
+        let c = load("ok", json).unwrap();
+        assert_eq!(c.addr(4), "10.0.0.2:9000".parse().unwrap());
+        assert_eq!(c.initial().members.keys().copied().collect::<Vec<_>>(), vec![3]);
+        assert_eq!((c.heartbeat, c.election_min), (20, RaftConfig::default().election_min));
+    }
+
+    /// Timings that can't work, and members nobody can reach, never make it past `load`.
+    #[test]
+    fn load_turns_down_what_cant_work() {
+        let cases = [
+            (r#"{"election_min": 300, "election_max": 300}"#, "election_min"),
+            (r#"{"heartbeat": 150}"#, "heartbeat"),
+            (r#"{"lease_reads": true, "lease_drift": 200}"#, "lease_drift"),
+            (r#"{"max_inflight": 0}"#, "max_inflight"),
+            (r#"{"max_batch": 0}"#, "max_batch"),
+            (r#"{"max_batch_bytes": 70000}"#, "max_batch_bytes"),
+            (r#"{"members": [0, 9]}"#, "member 9"),
+        ];
+        for (i, (json, why)) in cases.into_iter().enumerate() {
+            let err = load(&i.to_string(), json).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+            assert!(err.to_string().contains(why), "{json}: {err}");
+        }
+        // Drift only matters with leases on.
+        assert!(load("drift", r#"{"lease_drift": 200}"#).is_ok());
+        assert!(load("typo", r#"{"heartbeat": "fast"}"#).is_err());
+    }
+}
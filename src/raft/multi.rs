@@ -0,0 +1,199 @@
+//! Multi-Raft: lots of small groups in one process.
+//!
+//! Every group is its own `Server`, with its own term, log and config, but they all share
+//! the node's one UDP socket and one event loop. On the wire, a message is a
+//! `(group, Message)` pair, and timers come back as `(group, Timer, generation)`, so both
+//! can be routed to the right server. Each group keeps its state under `data_dir/group-{g}`.
+//!
+//! Keys map to groups with `shard`, which clients and servers have to agree on.
+
+use std::{collections::BTreeMap, path::Path};
+
+use hashbrown::HashMap;
+use message_io::{
+    network::{NetEvent, Transport},
+    node::{self, NodeEvent, NodeListener, NodeTask},
+};
+use serde_json::from_slice;
+
+use crate::StateMachine;
+
+use super::{server::Server, storage::Storage, Config, Message, RaftConfig, Signal};
+
+/// Which of `groups` groups owns `key`. FNV-1a, so it's the same everywhere.
+pub fn shard(key: &str, groups: usize) -> usize {
+    let hash = key.bytes().fold(0xcbf29ce484222325u64, |h, b| {
+        (h ^ b as u64).wrapping_mul(0x100000001b3)
+    });
+    (hash % groups as u64) as usize
+}
+
+/// Host server `id` of every group in `groups`, all on its address in `settings`.
+/// Runs until killed.
+///
+/// Groups can have different members. Messages for a group we're not in get dropped.
+pub fn run_groups<S: StateMachine>(
+    id: usize,
+    data_dir: &Path,
+    settings: &RaftConfig,
+    groups: BTreeMap<usize, Config>,
+) {
+    let (handler, listener) = node::split::<Signal>();
+    let (via, _) = handler
+        .network()
+        .listen(Transport::Udp, settings.addr(id))
+        .unwrap();
+
+    let servers: HashMap<usize, Server<S>> = groups
+        .into_iter()
+        .map(|(g, config)| {
+            let storage = Storage::open(&data_dir.join(format!("group-{g}")), id).unwrap();
+            let group = Some((g, via));
+            let server = Server::new(
+                id,
+                group,
+                config,
+                settings.clone(),
+                handler.clone(),
+                storage,
+            );
+            (g, server)
+        })
+        .collect();
+
+    route(servers, listener).wait();
+}
+
+/// Hands every message and timer to the server of its group, in the background.
+/// Anything for a group that isn't here is dropped.
+fn route<S: StateMachine>(
+    mut servers: HashMap<usize, Server<S>>,
+    listener: NodeListener<Signal>,
+) -> NodeTask {
+    listener.for_each_async(move |event| match event {
+        NodeEvent::Network(NetEvent::Message(ep, buf)) => {
+            if let Ok((g, msg)) = from_slice::<(usize, Message<S::Op>)>(buf) {
+                if let Some(server) = servers.get_mut(&g) {
+                    server.handle(ep, msg);
+                }
+            }
+        }
+        NodeEvent::Network(_) => {}
+        NodeEvent::Signal((g, t, generation)) => {
+            if let Some(server) = servers.get_mut(&g) {
+                server.signal(t, generation);
+            }
+        }
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        net::{SocketAddr, UdpSocket},
+        sync::mpsc,
+        time::Duration,
+    };
+
+    use serde_json::to_vec;
+
+    use super::*;
+    use crate::{
+        kv::{KvOp, KvStore},
+        raft::Campaign,
+    };
+
+    /// Clients and servers are different processes, maybe different builds.
+    /// The mapping can't depend on anything but the key.
+    #[test]
+    fn shards_are_fixed_and_spread_out() {
+        // FNV-1a of "hello" is 0xa430d84680aabd0b.
+        assert_eq!(shard("hello", 7), (0xa430d84680aabd0bu64 % 7) as usize);
+        assert_eq!(shard("hello", 1), 0);
+
+        let mut counts = [0; 4];
+        for i in 0..4000 {
+            counts[shard(&format!("k{i}"), 4)] += 1;
+        }
+        assert!(counts.iter().all(|c| (800..1200).contains(c)), "{counts:?}");
+    }
+
+    /// Two groups on one socket. Whatever comes in goes to the server of its group,
+    /// and whatever that server sends goes out tagged with it.
+    #[test]
+    fn messages_and_timers_reach_their_group() {
+        let dir = std::env::temp_dir().join(format!("raft-multi-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        let (handler, listener) = node::split::<Signal>();
+        let (via, addr) = handler
+            .network()
+            .listen(Transport::Udp, "127.0.0.1:0")
+            .unwrap();
+        let peer = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let config = Config {
+            members: [(0, addr), (1, peer.local_addr().unwrap())].into(),
+            ..Default::default()
+        };
+
+        let (tx, rx) = mpsc::channel::<(SocketAddr, Vec<u8>)>();
+        let servers = [0, 1]
+            .into_iter()
+            .map(|g| {
+                let storage = Storage::open(&dir.join(format!("group-{g}")), 0).unwrap();
+                let (config, settings) = (config.clone(), RaftConfig::default());
+                let mut s = Server::<KvStore>::new(
+                    0,
+                    Some((g, via)),
+                    config,
+                    settings,
+                    handler.clone(),
+                    storage,
+                );
+                let tx = tx.clone();
+                s.wire = Box::new(move |ep, buf| tx.send((ep.addr(), buf.to_vec())).unwrap());
+                (g, s)
+            })
+            .collect();
+        let task = route(servers, listener);
+
+        // Nobody answers, so both election timers go off and both groups ask for pre-votes.
+        let mut asked = Vec::<usize>::new();
+        while asked.len() < 2 {
+            let (_, buf) = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+            if let (g, Message::<KvOp>::PreVote(_)) = from_slice(&buf).unwrap() {
+                if !asked.contains(&g) {
+                    asked.push(g);
+                }
+            }
+        }
+
+        // Group 7 isn't here. Only 0 and 1 answer, in order.
+        let c = Campaign {
+            term: 9,
+            candidate_id: 1,
+            last_log_index: 0,
+            last_log_term: 0,
+            transfer: false,
+        };
+        for g in [7, 0, 1] {
+            peer.send_to(
+                &to_vec(&(g, Message::<KvOp>::PreVote(c.clone()))).unwrap(),
+                addr,
+            )
+            .unwrap();
+        }
+        let mut answered = Vec::<usize>::new();
+        while answered.len() < 2 {
+            let (to, buf) = rx.recv_timeout(Duration::from_secs(2)).unwrap();
+            if let (g, Message::<KvOp>::PreVoteReply(_)) = from_slice(&buf).unwrap() {
+                assert_eq!(to, peer.local_addr().unwrap());
+                answered.push(g);
+            }
+        }
+        assert_eq!(answered, vec![0, 1]);
+
+        handler.stop();
+        drop(task);
+        let _ = std::fs::remove_dir_all(&dir);
+    }
+}
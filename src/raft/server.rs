@@ -1,246 +1,1295 @@
 #![allow(dead_code)]
-use std::{net::SocketAddr, time::Duration};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    net::SocketAddr,
+    path::Path,
+    time::{Duration, Instant},
+};
 
-use hashbrown::HashMap;
+use hashbrown::{HashMap, HashSet};
 use message_io::{
     events::TimerId,
-    network::{Endpoint, NetEvent, Transport},
+    network::{Endpoint, NetEvent, ResourceId, Transport},
     node::{self, NodeEvent, NodeHandler},
 };
 use rand::distributions::{Distribution, Uniform};
 use serde_json::{from_slice, to_vec};
 
-use crate::{Params, ReplicaState};
+use crate::{session::Sessions, Operation, Params, StateMachine, SNAPSHOT_CHUNK};
 
 use super::{
-    dir::get_peers, Campaign, Heartbeat, Log, Message, Replicate, Reply, ServerState, Timer,
+    dir::get_peers,
+    storage::Storage,
+    Campaign, Change, Command, Config, Heartbeat, InstallSnapshot, Log, Message, RaftConfig,
+    Replicate, Reply, ServerState, Signal, Timer,
 };
 
-pub struct Server {
+/// One of a server's timers. At most one is live: setting it again cancels the last one,
+/// and bumps the generation so that a signal already on its way gets ignored.
+#[derive(Default)]
+struct Alarm {
+    id: Option<TimerId>,
+    generation: usize,
+}
+
+/// Leader only. How replication to one peer stands.
+struct Progress {
+    /// Next entry to send. Moves ahead as soon as a batch goes out, and back on trouble.
+    next: usize,
+    /// Last entry known to be replicated there.
+    matched: usize,
+    /// Batches out: last entry in each, and when it went.
+    inflight: VecDeque<(usize, Instant)>,
+}
+
+impl Progress {
+    fn new(next: usize) -> Self {
+        Self {
+            next,
+            matched: 0,
+            inflight: VecDeque::new(),
+        }
+    }
+}
+
+pub struct Server<S: StateMachine> {
     id: usize,
+    /// Multi-Raft only. Our group, and the node's one socket. Everything we send goes out
+    /// through that socket, tagged with the group.
+    group: Option<(usize, ResourceId)>,
     state: ServerState, // Look at enum variants
-    rst: ReplicaState,  // State of the replica
+    rst: Sessions<(SocketAddr, u64), S>, // State of the replica, one execution per (client, op)
     current_term: usize,
     voted_for: Option<usize>,           // Leader election.
-    log: Vec<Log>,                      // Replica<index, Log<term, ACTUAL SHIT>>
+    leader: Option<usize>,              // Who leads current_term, as far as we know.
+    log: Vec<Log<S::Op>>,               // Replica<index - offset, Log<term, ACTUAL SHIT>>
+    offset: usize,                      // index of log[0]. Everything up to it is in the snapshot.
+    snapshot: Vec<u8>,                  // of rst at offset, for followers that need it
+    incoming: (usize, Vec<u8>),         // snapshot being received: its last index, bytes so far
+    base: Config,                       // membership at offset
+    config: Config,                     // membership now: the last config in the log, or base
+    config_index: usize,                // where config came in
     commit_index: usize,                // index of highest committed entry
     last_applied: usize,                // index of highest applied entry
-    next_index: HashMap<usize, usize>,  // index of next log entry to send to each server
-    match_index: HashMap<usize, usize>, // index of highest log entry known to be replicated on server
+    progress: HashMap<usize, Progress>, // replication to each server, see Progress
 
+    /// Timings and limits.
+    settings: RaftConfig,
     u: rand::distributions::Uniform<f64>,
-    handler: NodeHandler<Timer>,
+    handler: NodeHandler<Signal>,
     peers: HashMap<usize, Endpoint>,
     clients: HashMap<SocketAddr, Endpoint>,
-    current_timer: Option<TimerId>,
-    pending: Vec<Message>,
+    /// Election timeout, or the quorum check for a leader.
+    election: Alarm,
+    /// Leader only, so that followers hear from us.
+    heartbeat: Alarm,
+    pending: Vec<Message<S::Op>>,
+    /// Candidate and pre-candidate only. Who said yes this round.
+    votes: HashSet<usize>,
+    /// Last time a leader got through to us. No pre-votes for anyone within an election timeout of it.
+    last_heard: Instant,
+    /// Leader only. Peers that answered since `quorum_checked`.
+    heard: HashSet<usize>,
+    quorum_checked: Instant,
+    /// Leader only. Handing over to this server, since then.
+    /// New requests wait in `pending` meanwhile.
+    transfer: Option<(usize, Instant)>,
+
+    /// Leader only. Current heartbeat round, when each recent one went out,
+    /// and the latest one each peer answered.
+    round: usize,
+    sent_at: BTreeMap<usize, Instant>,
+    acked: HashMap<usize, usize>,
+    /// Leader only. Reads waiting on a heartbeat round, with the commit index they have to see.
+    reads: Vec<(usize, usize, Command<S::Op>)>,
+    /// Leader only. No one else can be leader before this.
+    lease_until: Option<Instant>,
+    /// Leader only. Sent a `TimeoutNow` this term. The target can win whenever that gets
+    /// there, lease or not, so no more leases until the next term.
+    handed_over: bool,
+    /// `current_term`, `voted_for` and `log` are mirrored here.
+    storage: Storage,
+    /// Where `send` puts messages. The handler's network, unless someone swaps it out.
+    pub(super) wire: Wire,
 }
 
-impl Server {
-    fn new(id: usize, peers: HashMap<usize, Endpoint>, handler: NodeHandler<Timer>) -> Self {
+/// Puts an encoded message on its way to an endpoint.
+pub(super) type Wire = Box<dyn Fn(Endpoint, &[u8]) + Send>;
+
+impl<S: StateMachine> Server<S> {
+    /// `config` is only used if nothing on disk says otherwise.
+    pub(super) fn new(
+        id: usize,
+        group: Option<(usize, ResourceId)>,
+        config: Config,
+        settings: RaftConfig,
+        handler: NodeHandler<Signal>,
+        storage: Storage,
+    ) -> Self {
+        let recalled = storage.recall().unwrap();
+        let mut rst = Sessions::default();
+        let (base, snapshot) = match recalled.snapshot {
+            Some((base, snapshot)) => {
+                rst.restore(&snapshot);
+                (base, snapshot)
+            }
+            None => (config, vec![]),
+        };
         let mut out = Self {
             id,
+            group,
             state: ServerState::Follower,
-            rst: ReplicaState::default(),
-            current_term: 0,
-            voted_for: None,
-            log: vec![Log {
-                term: 0,
-                command: None,
-            }],
-            commit_index: 0,
-            last_applied: 0,
-            next_index: peers
-                .iter()
-                .map(|(a, _b)| (*a, 1))
-                .collect::<HashMap<_, _>>(),
-            match_index: peers
-                .iter()
-                .map(|(a, _b)| (*a, 0))
-                .collect::<HashMap<_, _>>(),
-            u: Uniform::new(150.0, 300.0),
+            rst,
+            current_term: recalled.term,
+            voted_for: recalled.voted_for,
+            leader: None,
+            log: recalled.log,
+            offset: recalled.offset,
+            snapshot,
+            incoming: (0, vec![]),
+            base: base.clone(),
+            config: Config::default(),
+            config_index: recalled.offset,
+            commit_index: recalled.offset,
+            last_applied: recalled.offset,
+            progress: HashMap::new(),
+            u: Uniform::new(settings.election_min as f64, settings.election_max as f64),
+            settings,
+            peers: HashMap::new(),
+            wire: {
+                let handler = handler.clone();
+                Box::new(move |ep, buf| {
+                    handler.network().send(ep, buf);
+                })
+            },
             handler,
-            peers,
             clients: HashMap::new(),
-            current_timer: None,
+            election: Alarm::default(),
+            heartbeat: Alarm::default(),
             pending: vec![],
+            votes: HashSet::new(),
+            last_heard: Instant::now(),
+            heard: HashSet::new(),
+            quorum_checked: Instant::now(),
+            transfer: None,
+            round: 0,
+            sent_at: BTreeMap::new(),
+            acked: HashMap::new(),
+            reads: vec![],
+            lease_until: None,
+            handed_over: false,
+            storage,
         };
 
+        out.peers = out.connect_all(&base);
+        for p in out.peers.keys() {
+            out.progress.insert(*p, Progress::new(1));
+        }
+        out.refresh_config();
+
         // Start the timeouts.
         out.reset_timeout();
-        out.reset_heartbeat();
         out
     }
 
-    fn empty_decree(&self) {
-        let hb = Heartbeat {
+    /// Index of the last entry.
+    fn last_index(&self) -> usize {
+        self.offset + self.log.len() - 1
+    }
+
+    /// Term of entry `i`. None if it's gone into the snapshot.
+    fn term_at(&self, i: usize) -> Option<usize> {
+        i.checked_sub(self.offset).map(|i| self.log[i].term)
+    }
+
+    /// Membership as of entry `i`, and where it came in.
+    fn config_at(&self, i: usize) -> (Config, usize) {
+        self.log[..=i - self.offset]
+            .iter()
+            .enumerate()
+            .rev()
+            .find_map(|(j, l)| l.config.clone().map(|c| (c, self.offset + j)))
+            .unwrap_or((self.base.clone(), self.offset))
+    }
+
+    /// Picks up the latest config in the log, and connects to or forgets peers to match.
+    /// Call whenever the log changes.
+    fn refresh_config(&mut self) {
+        let (config, index) = self.config_at(self.last_index());
+        if config == self.config {
+            self.config_index = index;
+            return;
+        }
+        let next = self.last_index() + 1;
+        for (id, ep) in self.connect_all(&config) {
+            if !self.peers.contains_key(&id) {
+                self.peers.insert(id, ep);
+                self.progress.insert(id, Progress::new(next));
+            }
+        }
+        self.peers.retain(|id, _| config.contains(*id));
+        self.progress.retain(|id, _| config.contains(*id));
+        self.config = config;
+        self.config_index = index;
+        // Promoted, or made a learner.
+        if matches!(self.state, ServerState::Follower | ServerState::Learner) {
+            self.state = self.follower();
+        }
+    }
+
+    fn is_member(&self) -> bool {
+        self.config.members.contains_key(&self.id)
+    }
+
+    /// What we are when not leading or campaigning.
+    fn follower(&self) -> ServerState {
+        match self.config.learners.contains_key(&self.id) {
+            true => ServerState::Learner,
+            false => ServerState::Follower,
+        }
+    }
+
+    /// Leader only. Appends a client command and sends it out.
+    /// Unless no follower could ever be sent it.
+    fn append(&mut self, cmd: Command<S::Op>) {
+        let log = Log {
             term: self.current_term,
-            leader_id: self.id,
-            prev_log_index: self.log.len() - 1,
-            prev_log_term: self.log.last().unwrap().term,
-            leader_commit: self.commit_index,
+            command: Some(cmd),
+            config: None,
         };
+        if to_vec(&(self.last_index() + 1, &log)).unwrap().len() > self.settings.max_batch_bytes {
+            let cmd = log.command.unwrap();
+            let ep = self.client(cmd.client);
+            self.send(ep, &Message::TooBig(cmd));
+            return;
+        }
+        self.log.push(log);
+        self.save_log(self.last_index());
+        self.decree();
+    }
 
-        for p in self.peers.iter() {
-            self.handler.network().send(
-                *p.1,
-                &to_vec(&Message::Heartbeat(Replicate {
-                    hb,
-                    entries: vec![],
-                }))
-                .unwrap(),
-            );
+    /// Leader only. Starts handing leadership to `target`. Once its log matches ours it gets a
+    /// `TimeoutNow`, and wins the election before anyone else's timer runs out.
+    fn transfer(&mut self, target: usize) {
+        let member = self.config.members.contains_key(&target);
+        if target == self.id || !member || self.transfer.is_some() {
+            return;
         }
-        self.reset_heartbeat();
+        self.transfer = Some((target, Instant::now()));
+        self.decree();
+        self.try_hand_over();
     }
 
-    fn decree(&self) {
-        let mut hb = Heartbeat {
+    /// Sends the `TimeoutNow` if the target has caught up.
+    fn try_hand_over(&mut self) {
+        let Some((target, _)) = self.transfer else {
+            return;
+        };
+        if self.progress.get(&target).map(|p| p.matched) == Some(self.last_index()) {
+            self.handed_over = true;
+            self.send(self.peers[&target], &Message::TimeoutNow(self.current_term));
+        }
+    }
+
+    /// The target didn't take over within an election timeout. Carry on as leader,
+    /// with whatever came in meanwhile.
+    fn abort_transfer(&mut self) {
+        match self.transfer {
+            Some((_, since)) if since.elapsed() >= self.election_timeout() => {}
+            _ => return,
+        }
+        self.transfer = None;
+        self.replay();
+    }
+
+    /// Leader only. Goes through whatever was held while there was no leader, or while
+    /// handing over. Another transfer in there holds the rest again.
+    fn replay(&mut self) {
+        for msg in std::mem::take(&mut self.pending) {
+            match msg {
+                _ if self.transfer.is_some() => self.hold(msg),
+                Message::Request(cmd) if cmd.op.is_read() => self.read(cmd),
+                Message::Request(cmd) => self.append(cmd),
+                Message::Reconfigure(change) => self.reconfigure(&change),
+                Message::Transfer(target) => self.transfer(target),
+                _ => {}
+            }
+        }
+    }
+
+    /// Leader only. Appends the new config, which takes effect right away.
+    /// One change at a time: the last one has to be committed before the next goes in.
+    /// Not before something from our own term is committed either, or a change the last leader
+    /// left uncommitted could still win out over ours, and the majorities stop overlapping.
+    fn reconfigure(&mut self, change: &Change) {
+        let settled = self.term_at(self.commit_index) == Some(self.current_term);
+        if self.config_index > self.commit_index || !settled {
+            return;
+        }
+        let mut config = self.config.clone();
+        config.apply(change);
+        if config == self.config {
+            return;
+        }
+        self.log.push(Log {
             term: self.current_term,
-            leader_id: self.id,
-            prev_log_index: 0,
-            prev_log_term: 0,
-            leader_commit: self.commit_index,
+            command: None,
+            config: Some(config),
+        });
+        self.save_log(self.last_index());
+        self.refresh_config();
+        self.decree();
+    }
+
+    /// Heartbeat. Everyone hears from us this round: entries if they're due some,
+    /// otherwise nothing past what they're known to have.
+    fn empty_decree(&mut self) {
+        self.next_round();
+        let retransmit = Duration::from_millis(self.settings.retransmit);
+        let peers = self.peers.keys().copied().collect::<Vec<_>>();
+        for p in peers {
+            let pr = self.progress.get_mut(&p).unwrap();
+            if pr.inflight.front().is_some_and(|(_, t)| t.elapsed() >= retransmit) {
+                pr.next = pr.matched + 1;
+                pr.inflight.clear();
+            }
+            if self.replicate(p) {
+                continue;
+            }
+            let prev = self.progress[&p].matched.max(self.offset);
+            let hb = Heartbeat {
+                term: self.current_term,
+                leader_id: self.id,
+                prev_log_index: prev,
+                prev_log_term: self.term_at(prev).unwrap(),
+                leader_commit: self.commit_index,
+                round: self.round,
+            };
+            let msg = Message::Heartbeat(Replicate {
+                hb,
+                entries: vec![],
+            });
+            self.send(self.peers[&p], &msg);
+        }
+        self.schedule_heartbeat();
+    }
+
+    fn next_round(&mut self) -> usize {
+        self.round += 1;
+        self.sent_at.insert(self.round, Instant::now());
+        self.round
+    }
+
+    /// Latest round a majority answered, us included. We were leader at least until it went out.
+    fn confirmed_round(&self) -> usize {
+        let mut rounds = self
+            .acked
+            .iter()
+            .filter(|(p, _)| self.config.members.contains_key(*p))
+            .map(|(_, r)| *r)
+            .collect::<Vec<_>>();
+        if self.is_member() {
+            rounds.push(self.round);
+        }
+        rounds.sort_unstable_by(|a, b| b.cmp(a));
+        rounds.get(self.config.quorum() - 1).copied().unwrap_or(0)
+    }
+
+    /// A peer answered a heartbeat round. Moves the lease along, and maybe some reads.
+    fn ack(&mut self, from: usize, round: usize) {
+        let r = self.acked.entry(from).or_default();
+        *r = (*r).max(round);
+        let confirmed = self.confirmed_round();
+        if let Some(sent) = self.sent_at.get(&confirmed) {
+            // Nobody votes within an election timeout of hearing from us, unless we sent
+            // them a `TimeoutNow`. So nobody else wins an election before then either.
+            let drift = Duration::from_millis(self.settings.lease_drift);
+            let len = self.election_timeout().saturating_sub(drift);
+            self.lease_until = Some(*sent + len);
+        }
+        self.sent_at = self.sent_at.split_off(&confirmed);
+        self.serve_reads();
+    }
+
+    fn holds_lease(&self) -> bool {
+        let valid = self.lease_until.is_some_and(|t| Instant::now() < t);
+        self.settings.lease_reads && self.transfer.is_none() && !self.handed_over && valid
+    }
+
+    /// Leader only. Reads skip the log: they wait for a heartbeat round to confirm we're
+    /// still leader (or not even that, under a lease), then for `last_applied` to reach
+    /// what was committed when they came in.
+    fn read(&mut self, cmd: Command<S::Op>) {
+        if self.holds_lease() {
+            self.reads.push((0, self.commit_index, cmd));
+        } else {
+            self.reads.push((self.round + 1, self.commit_index, cmd));
+            self.empty_decree();
+        }
+        self.serve_reads();
+    }
+
+    fn serve_reads(&mut self) {
+        // Until something from our own term is committed,
+        // we can't tell what the last leader committed.
+        if self.term_at(self.commit_index) != Some(self.current_term) {
+            return;
+        }
+        let confirmed = self.confirmed_round();
+        let (ready, rest) = std::mem::take(&mut self.reads)
+            .into_iter()
+            .partition(|(round, index, _)| *round <= confirmed && *index <= self.last_applied);
+        self.reads = rest;
+        for (_, _, cmd) in ready {
+            let res = self.rst.state().apply(&cmd.op);
+            self.respond(cmd, res);
+        }
+    }
+
+    fn respond(&mut self, cmd: Command<S::Op>, res: <S::Op as Operation>::Output) {
+        let ep = self.client(cmd.client);
+        self.send(ep, &Message::Response(cmd, res));
+    }
+
+    /// Tells a client we aren't the one to talk to, and who is if we know.
+    fn not_leader(&mut self, cmd: Command<S::Op>, leader: Option<usize>) {
+        let hint = leader.and_then(|l| self.config.servers().find(|(i, _)| **i == l));
+        let hint = hint.map(|(_, addr)| *addr);
+        let ep = self.client(cmd.client);
+        self.send(ep, &Message::NotLeader { cmd, hint });
+    }
+
+    fn client(&mut self, sock: SocketAddr) -> Endpoint {
+        if let Some(ep) = self.clients.get(&sock) {
+            return *ep;
+        }
+        let ep = self.connect(sock);
+        self.clients.insert(sock, ep);
+        ep
+    }
+
+    fn connect(&self, addr: SocketAddr) -> Endpoint {
+        match self.group {
+            Some((_, via)) => Endpoint::from_listener(via, addr),
+            None => self.handler.network().connect(Transport::Udp, addr).unwrap().0,
+        }
+    }
+
+    /// Endpoints for everyone in `config` but us.
+    fn connect_all(&self, config: &Config) -> HashMap<usize, Endpoint> {
+        match self.group {
+            Some(_) => config
+                .servers()
+                .filter(|(i, _)| **i != self.id)
+                .map(|(i, addr)| (*i, self.connect(*addr)))
+                .collect(),
+            None => get_peers(self.id, config, &self.handler),
+        }
+    }
+
+    /// Every outgoing message goes through here.
+    /// Term and vote hit the disk first, so nobody ever hears about state we could forget.
+    fn send(&self, ep: Endpoint, msg: &Message<S::Op>) {
+        self.storage
+            .save_state(self.current_term, self.voted_for)
+            .unwrap();
+        let buf = match self.group {
+            Some((group, _)) => to_vec(&(group, msg)),
+            None => to_vec(msg),
+        };
+        (self.wire)(ep, &buf.unwrap());
+    }
+
+    /// Persists entries `from..`. Call before anyone is told about the new entries.
+    fn save_log(&self, from: usize) {
+        self.storage
+            .save_log(self.offset, from.max(self.offset), &self.log)
+            .unwrap();
+    }
+
+    /// Folds everything applied into a snapshot and drops that part of the log.
+    fn compact(&mut self) {
+        if self.last_applied - self.offset < self.settings.snapshot_every {
+            return;
+        }
+        let term = self.term_at(self.last_applied).unwrap();
+        let (base, _) = self.config_at(self.last_applied);
+        self.snapshot = self.rst.snapshot();
+        self.storage
+            .save_snapshot(self.last_applied, term, &base, &self.snapshot)
+            .unwrap();
+        self.log.drain(..self.last_applied - self.offset);
+        self.offset = self.last_applied;
+        self.base = base;
+    }
+
+    /// Follower side of `InstallSnapshot`. Anything in the log past the snapshot stays if it agrees with it.
+    ///
+    /// Pieces have to come in order. Anything else is dropped, and the leader starts over from
+    /// the first one when it retransmits.
+    fn install(&mut self, snap: InstallSnapshot) {
+        // Already applied further than that.
+        if snap.last_index <= self.last_applied {
+            return;
+        }
+        if snap.offset == 0 {
+            self.incoming = (snap.last_index, vec![]);
+        }
+        if self.incoming.0 != snap.last_index || self.incoming.1.len() != snap.offset {
+            return;
+        }
+        self.incoming.1.extend_from_slice(&snap.data);
+        if !snap.done {
+            return;
+        }
+        let (_, data) = std::mem::take(&mut self.incoming);
+        if snap.last_index <= self.last_index() && self.term_at(snap.last_index) == Some(snap.last_term) {
+            self.log.drain(..snap.last_index - self.offset);
+        } else {
+            self.log = vec![Log {
+                term: snap.last_term,
+                command: None,
+                config: None,
+            }];
+        }
+        self.offset = snap.last_index;
+        self.rst.restore(&data);
+        self.storage
+            .save_snapshot(snap.last_index, snap.last_term, &snap.config, &data)
+            .unwrap();
+        self.save_log(self.offset);
+        self.base = snap.config;
+        self.refresh_config();
+        self.snapshot = data;
+        self.commit_index = self.commit_index.max(snap.last_index);
+        self.last_applied = snap.last_index;
+    }
+
+    /// Sends out whatever's new, as far as each follower's window goes.
+    fn decree(&mut self) {
+        self.next_round();
+        let peers = self.peers.keys().copied().collect::<Vec<_>>();
+        for p in peers {
+            self.replicate(p);
+        }
+        self.schedule_heartbeat();
+    }
+
+    /// Leader only. Sends `p` batches from its `next` on, until its window is full or it has
+    /// everything. False if nothing went out.
+    fn replicate(&mut self, p: usize) -> bool {
+        // Gone from the config since.
+        let Some(&ep) = self.peers.get(&p) else {
+            return false;
+        };
+        let last = self.last_index();
+        let mut sent = false;
+        loop {
+            let pr = &self.progress[&p];
+            if pr.inflight.len() >= self.settings.max_inflight || pr.next > last {
+                return sent;
+            }
+            let next = pr.next;
+            sent = true;
+
+            // Needs entries we no longer have.
+            if next <= self.offset {
+                let pieces = self.snapshot.len().div_ceil(SNAPSHOT_CHUNK).max(1);
+                for i in 0..pieces {
+                    let start = i * SNAPSHOT_CHUNK;
+                    let end = (start + SNAPSHOT_CHUNK).min(self.snapshot.len());
+                    let snap = InstallSnapshot {
+                        term: self.current_term,
+                        leader_id: self.id,
+                        last_index: self.offset,
+                        last_term: self.log[0].term,
+                        config: self.base.clone(),
+                        offset: start,
+                        done: i + 1 == pieces,
+                        data: self.snapshot[start..end].to_vec(),
+                    };
+                    self.send(ep, &Message::InstallSnapshot(snap));
+                }
+                // No reply to a snapshot. Carry on from right after it,
+                // a rejection brings us back here.
+                let pr = self.progress.get_mut(&p).unwrap();
+                pr.next = self.offset + 1;
+                pr.inflight.push_back((self.offset, Instant::now()));
+                continue;
+            }
+
+            let (max, max_bytes) = (self.settings.max_batch, self.settings.max_batch_bytes);
+            let mut entries = vec![];
+            let mut bytes = 0;
+            for i in next..=last {
+                let log = &self.log[i - self.offset];
+                bytes += to_vec(&(i, log)).unwrap().len();
+                if !entries.is_empty() && (entries.len() >= max || bytes > max_bytes) {
+                    break;
+                }
+                entries.push((i, log.clone()));
+            }
+            let end = next + entries.len() - 1;
+            let hb = Heartbeat {
+                term: self.current_term,
+                leader_id: self.id,
+                prev_log_index: next - 1,
+                prev_log_term: self.term_at(next - 1).unwrap(),
+                leader_commit: self.commit_index,
+                round: self.round,
+            };
+            self.send(ep, &Message::Heartbeat(Replicate { hb, entries }));
+            let pr = self.progress.get_mut(&p).unwrap();
+            pr.next = end + 1;
+            pr.inflight.push_back((end, Instant::now()));
+        }
+    }
+
+    /// Shortest election timeout there is.
+    fn election_timeout(&self) -> Duration {
+        Duration::from_millis(self.settings.election_min)
+    }
+
+    /// Whether a candidate with this last entry has a log at least as good as ours.
+    fn up_to_date(&self, c: &Campaign) -> bool {
+        let last_term = self.log.last().unwrap().term;
+        c.last_log_term > last_term
+            || (c.last_log_term == last_term && c.last_log_index >= self.last_index())
+    }
+
+    /// Asks around before bumping the term. A server that can't win, say one cut off from
+    /// the rest, keeps its term and can't force a working leader out when it comes back.
+    fn pre_campaign(&mut self) {
+        // Not in the cluster (yet, or any more). Heartbeats restart the timer if that changes.
+        if !self.is_member() {
+            return;
+        }
+        if self.config.quorum() <= 1 {
+            self.campaign(false);
+            return;
+        }
+        self.state = ServerState::PreCandidate(1);
+        self.votes.clear();
+
+        let cp = Campaign {
+            term: self.current_term + 1,
+            candidate_id: self.id,
+            last_log_index: self.last_index(),
+            last_log_term: self.log.last().unwrap().term,
+            transfer: false,
         };
 
         for p in self.peers.iter() {
-            let entries = self
-                .log
-                .iter()
-                .enumerate()
-                .skip(self.next_index[p.0] - 1)
-                .map(|x| (x.0, x.1.clone()))
-                .collect::<Vec<_>>();
-            hb.prev_log_index = self.next_index[p.0] - 1;
-            hb.prev_log_term = self.log[hb.prev_log_index].term;
-            self.handler.network().send(
-                *p.1,
-                &to_vec(&Message::Heartbeat(Replicate { hb, entries })).unwrap(),
-            );
-        }
-        self.reset_heartbeat();
-    }
-
-    fn campaign(&mut self) {
+            self.send(*p.1, &Message::PreVote(cp.clone()));
+        }
+        self.reset_timeout();
+    }
+
+    /// Would vote for `c` if it ran now. Not if we still hear from a leader.
+    fn pre_vote(&self, ep: Endpoint, c: &Campaign) {
+        let success = c.term > self.current_term
+            && self.up_to_date(c)
+            && self.state != ServerState::Leader
+            && self.last_heard.elapsed() >= self.election_timeout();
+        let rep = &Message::PreVoteReply(Reply {
+            from: self.id,
+            success,
+            term: self.current_term,
+            ..Default::default()
+        });
+        self.send(ep, rep);
+    }
+
+    /// Leader only, once per election timeout. Steps down unless a majority answered since last time.
+    /// Stops a leader cut off from the rest from taking requests it can never commit.
+    fn check_quorum(&mut self) {
+        if self.quorum_checked.elapsed() < self.election_timeout() {
+            return;
+        }
+        let heard = self
+            .heard
+            .iter()
+            .filter(|p| self.config.members.contains_key(*p))
+            .count();
+        let answered = heard + self.is_member() as usize;
+        if answered < self.config.quorum() {
+            self.stop_timer(Timer::Heartbeat);
+            self.state = self.follower();
+            self.leader = None;
+        }
+        self.heard.clear();
+        self.quorum_checked = Instant::now();
+    }
+
+    /// `transfer`: the leader sent us a `TimeoutNow`, so it's fine that others still hear from it.
+    fn campaign(&mut self, transfer: bool) {
+        // Not in the cluster (yet, or any more). Heartbeats restart the timer if that changes.
+        if !self.is_member() {
+            return;
+        }
         self.current_term += 1;
         self.voted_for = Some(self.id);
+        self.leader = None;
         self.state = ServerState::Candidate(1);
+        self.votes.clear();
 
         let cp = Campaign {
             term: self.current_term,
             candidate_id: self.id,
-            last_log_index: self.log.len() - 1,
+            last_log_index: self.last_index(),
             last_log_term: self.log.last().unwrap().term,
+            transfer,
         };
 
         for p in self.peers.iter() {
-            self.handler
-                .network()
-                .send(*p.1, &to_vec(&Message::Campaign(cp.clone())).unwrap());
+            self.send(*p.1, &Message::Campaign(cp.clone()));
         }
         self.reset_timeout();
     }
 
     fn crown(&mut self) {
         self.state = ServerState::Leader;
-        self.voted_for = None;
+        self.leader = Some(self.id);
+        self.transfer = None;
+        self.heard.clear();
+        self.quorum_checked = Instant::now();
+        self.acked.clear();
+        self.reads.clear();
+        self.lease_until = None;
+        self.handed_over = false;
         // println!("Crowned {}", self.id);
-        self.empty_decree();
-        for (_a, b) in self.next_index.iter_mut() {
-            *b = self.log.len();
+        let next = self.last_index() + 1;
+        for pr in self.progress.values_mut() {
+            *pr = Progress::new(next);
+        }
+        // A no-op of our own, so that there's something from this term to commit. Reads wait on it.
+        self.log.push(Log {
+            term: self.current_term,
+            command: None,
+            config: None,
+        });
+        self.save_log(self.last_index());
+        self.decree();
+        // Whatever came in during the election.
+        self.replay();
+    }
+
+    /// Someone has a term at least as new as ours. A new term starts without a vote or a leader.
+    fn step_down(&mut self, term: usize) {
+        if term > self.current_term {
+            self.current_term = term;
+            self.voted_for = None;
+            self.leader = None;
+        }
+        if self.state == ServerState::Leader {
+            self.stop_timer(Timer::Heartbeat);
+        }
+        self.state = self.follower();
+    }
+
+    /// Passes `msg` on to the leader, or holds on to it until we know who that is.
+    /// A client gets pointed at the leader, so that it goes there directly next time.
+    fn forward(&mut self, msg: Message<S::Op>) {
+        let Some(leader) = self.leader.filter(|l| self.peers.contains_key(l)) else {
+            self.hold(msg);
+            return;
+        };
+        self.send(self.peers[&leader], &msg);
+        if let Message::Request(cmd) = msg {
+            self.not_leader(cmd, Some(leader));
         }
     }
 
+    /// Keeps `msg` for whoever leads next. A request that doesn't fit is turned away,
+    /// and the client can try again later or elsewhere. Anything else is just dropped.
+    fn hold(&mut self, msg: Message<S::Op>) {
+        if self.pending.len() < self.settings.pending_max {
+            self.pending.push(msg);
+        } else if let Message::Request(cmd) = msg {
+            self.not_leader(cmd, None);
+        }
+    }
+
+    /// A fresh, random election timeout, in place of whatever was running.
     fn reset_timeout(&mut self) {
-        let rng = &mut rand::thread_rng();
-        self.current_timer = Some(self.handler.signals().send_with_timer(
-            Timer::Election,
-            Duration::from_millis(self.u.sample(rng) as u64),
-        ));
+        let after = Duration::from_millis(self.u.sample(&mut rand::thread_rng()) as u64);
+        self.set_timer(Timer::Election, after);
     }
 
-    fn reset_heartbeat(&self) {
-        self.handler
-            .signals()
-            .send_with_timer(Timer::Heartbeat, Duration::from_millis(50));
+    /// Next heartbeat, unless one is already coming.
+    fn schedule_heartbeat(&mut self) {
+        if self.heartbeat.id.is_none() {
+            self.set_timer(Timer::Heartbeat, Duration::from_millis(self.settings.heartbeat));
+        }
+    }
+
+    fn alarm(&mut self, t: Timer) -> &mut Alarm {
+        match t {
+            Timer::Election => &mut self.election,
+            Timer::Heartbeat => &mut self.heartbeat,
+        }
     }
 
-    fn reject(&mut self, ep: Endpoint) {
+    fn set_timer(&mut self, t: Timer, after: Duration) {
+        self.stop_timer(t);
+        let signal = (self.group_id(), t, self.alarm(t).generation);
+        let id = self.handler.signals().send_with_timer(signal, after);
+        self.alarm(t).id = Some(id);
+    }
+
+    fn stop_timer(&mut self, t: Timer) {
+        let signals = self.handler.signals().clone();
+        let alarm = self.alarm(t);
+        if let Some(id) = alarm.id.take() {
+            signals.cancel_timer(id);
+        }
+        alarm.generation += 1;
+    }
+
+    fn group_id(&self) -> usize {
+        self.group.map_or(0, |(g, _)| g)
+    }
+
+    fn reject(&self, ep: Endpoint, round: usize) {
         let rep = &Message::ServerReply(Reply {
             from: self.id,
             success: false,
             term: self.current_term,
+            round,
+            ..Default::default()
         });
-        self.handler.network().send(ep, &to_vec(rep).unwrap());
-        self.voted_for = None;
+        self.send(ep, rep);
     }
 
     fn vote(&mut self, ep: Endpoint, c: Campaign) {
-        if let Some(t) = self.current_timer {
-            self.handler.signals().cancel_timer(t);
-        }
         self.current_term = c.term;
         self.voted_for = Some(c.candidate_id);
-        self.state = ServerState::Follower;
+        self.state = self.follower();
         let rep = &Message::ServerReply(Reply {
             from: self.id,
             success: true,
             term: self.current_term,
+            ..Default::default()
         });
-        self.handler.network().send(ep, &to_vec(rep).unwrap());
+        self.send(ep, rep);
         self.reset_timeout();
     }
 
-    fn accept(&self, ep: Endpoint) {
+    /// Took the leader's entries. Everything up to `match_index` now agrees with its log.
+    fn accept(&self, ep: Endpoint, round: usize, match_index: usize) {
         let rep = &Message::ServerReply(Reply {
             from: self.id,
             success: true,
             term: self.current_term,
+            round,
+            match_index,
+            ..Default::default()
+        });
+        self.send(ep, rep);
+    }
+
+    /// Right leader, but our log doesn't have its entry at `prev`. Tells it where to pick up from.
+    fn mismatch(&self, ep: Endpoint, round: usize, prev: usize) {
+        let term = match prev <= self.last_index() {
+            true => self.term_at(prev),
+            false => None,
+        };
+        let (conflict_term, conflict_index) = match term {
+            Some(t) => {
+                // Back to the first entry of that term. The snapshot counts as matching.
+                let mut first = prev;
+                while first > self.offset + 1 && self.term_at(first - 1) == Some(t) {
+                    first -= 1;
+                }
+                (Some(t), first)
+            }
+            _ => (None, self.last_index() + 1),
+        };
+        let rep = &Message::ServerReply(Reply {
+            from: self.id,
+            success: false,
+            term: self.current_term,
+            round,
+            conflict_term,
+            conflict_index,
+            ..Default::default()
         });
-        self.handler.network().send(ep, &to_vec(rep).unwrap());
+        self.send(ep, rep);
+    }
+
+    /// Leader side of `accept`. Replies can come out of order, so only ever move forward.
+    /// Batches up to `index` are through, which makes room for more.
+    fn matched(&mut self, from: usize, index: usize) {
+        let Some(pr) = self.progress.get_mut(&from) else {
+            return;
+        };
+        pr.matched = pr.matched.max(index);
+        pr.next = pr.next.max(index + 1);
+        while pr.inflight.front().is_some_and(|(end, _)| *end <= index) {
+            pr.inflight.pop_front();
+        }
+    }
+
+    /// Leader side of `mismatch`. If we have entries from the conflicting term, the follower
+    /// may share some of them, so we resend from past our last one. Otherwise the whole term goes.
+    fn back_off(&mut self, res: &Reply) {
+        let next = match res.conflict_term {
+            Some(t) => (self.offset..=self.last_index())
+                .rev()
+                .find(|i| self.term_at(*i) == Some(t))
+                .map_or(res.conflict_index, |i| i + 1),
+            None => res.conflict_index,
+        };
+        // Never past what we have, and never below 1.
+        let next = next.clamp(1, self.last_index() + 1);
+        let Some(pr) = self.progress.get_mut(&res.from) else {
+            return;
+        };
+        // Answers a batch from before the last retry. It has at least `matched` already.
+        if next <= pr.matched {
+            return;
+        }
+        // Everything still out was built on the wrong guess.
+        pr.next = next;
+        pr.inflight.clear();
     }
 
     fn perform(&mut self) {
         for q in self.last_applied + 1..=self.commit_index {
             // perform
-            let cmd = self.log[q].command.clone();
+            let cmd = self.log[q - self.offset].command.clone();
             if cmd.is_none() {
                 continue;
             }
             let cmd = cmd.unwrap();
-            let (state, _res) = ReplicaState::triv(cmd.op.clone())(&self.rst);
-            self.rst = state;
+            let Some(res) = self.rst.execute((cmd.client, cmd.session), cmd.op_id, &cmd.op) else {
+                continue;
+            };
             if self.state == ServerState::Leader {
-                let sock = cmd.client;
-                if let Some(ep) = self.clients.get(&sock) {
-                    self.handler
-                        .network()
-                        .send(*ep, &to_vec(&Message::Response(cmd.clone())).unwrap());
-                } else {
-                    let ep = self
-                        .handler
-                        .network()
-                        .connect(Transport::Udp, sock)
-                        .unwrap()
-                        .0;
-                    self.clients.insert(sock, ep);
-                    self.handler
-                        .network()
-                        .send(ep, &to_vec(&Message::Response(cmd.clone())).unwrap());
-                };
+                self.respond(cmd, res);
             }
         }
         self.last_applied = self.commit_index;
+        self.compact();
+        if self.state == ServerState::Leader {
+            self.serve_reads();
+        }
+
+        // Removed ourselves, and that's now committed. Time to go.
+        if self.state == ServerState::Leader && !self.is_member() && self.commit_index >= self.config_index {
+            self.stop_timer(Timer::Heartbeat);
+            self.state = self.follower();
+            self.leader = None;
+        }
     }
+
+    /// Everything a server does with a message.
+    pub(super) fn handle(&mut self, ep: Endpoint, msg: Message<S::Op>) {
+        match msg {
+            // If leader, decree. Else, redirect to leader.
+            Message::Request(ref cmd) => {
+                // dbg!(&msg);
+                match self.state {
+                    ServerState::Follower | ServerState::Learner => self.forward(msg),
+                    ServerState::PreCandidate(_) | ServerState::Candidate(_) => {
+                        self.hold(msg);
+                    },
+                    // Handing over. The new leader gets it.
+                    ServerState::Leader if self.transfer.is_some() => {
+                        self.hold(msg);
+                    },
+                    ServerState::Leader if cmd.op.is_read() => {
+                        self.read(cmd.clone())
+                    },
+                    ServerState::Leader => self.append(cmd.clone()),
+                }
+            }
+
+            // Same routing as a request.
+            Message::Reconfigure(ref change) => match self.state {
+                ServerState::Leader if self.transfer.is_none() => {
+                    self.reconfigure(change)
+                }
+                ServerState::Leader => self.hold(msg),
+                _ => self.forward(msg),
+            },
+            Message::Transfer(target) => match self.state {
+                ServerState::Leader if self.transfer.is_none() => {
+                    self.transfer(target)
+                }
+                ServerState::Leader => self.hold(msg),
+                _ => self.forward(msg),
+            },
+
+            // A server can never receive a response.
+            // The leader responds to the client directly.
+            // The client socket address is contained in the command.
+            Message::Response(..) | Message::NotLeader { .. } | Message::TooBig(_) => {
+                unreachable!()
+            }
+            // Add to log
+            Message::Heartbeat(rep) => {
+                // println!("HB, {}", rep.entries.len());
+                // Old leader
+                if rep.hb.term < self.current_term {
+                    // println!("{}@{} Rejected {}@{}", id, self.current_term, rep.hb.leader_id, rep.hb.term);
+                    self.reject(ep, rep.hb.round);
+                    return;
+                } else if self.last_index() < rep.hb.prev_log_index // Old log, send previous stuff also
+                || self.term_at(rep.hb.prev_log_index).is_some_and(|t| t != rep.hb.prev_log_term) // Log conflict, send previous stuff also
+                {
+                    // So that pending messages are not lost.
+                    self.step_down(rep.hb.term);
+                    self.leader = Some(rep.hb.leader_id);
+                    self.last_heard = Instant::now();
+                    // println!("{} unmerge {}", id, rep.hb.leader_id);
+                    self.mismatch(ep, rep.hb.round, rep.hb.prev_log_index);
+                    self.reset_timeout();
+                    return;
+                } else {
+                    // println!("{} Accepted {}", id, rep.hb.leader_id);
+                    self.step_down(rep.hb.term);
+                    self.leader = Some(rep.hb.leader_id);
+                    self.last_heard = Instant::now();
+                    for (i, l) in rep.entries.iter() {
+                        if *i <= self.offset {
+                            // Already in the snapshot.
+                            continue;
+                        } else if *i <= self.last_index() {
+                            // Same term, same entry. Could be an old heartbeat,
+                            // so keep whatever follows.
+                            let at = *i - self.offset;
+                            if self.log[at].term == l.term {
+                                continue;
+                            }
+                            // Conflict. Everything from here on goes.
+                            self.log.truncate(at);
+                            self.log.push(l.clone());
+                        } else {
+                            // New
+                            self.log.push(l.clone());
+                        }
+                    }
+                    if let Some((first, _)) = rep.entries.first() {
+                        self.save_log(*first);
+                        self.refresh_config();
+                    }
+
+                    for msg in std::mem::take(&mut self.pending) {
+                        self.forward(msg);
+                    }
+                }
+
+                // Only what we know matches the leader. Past that, our log may still be stale.
+                let matched = rep
+                    .entries
+                    .last()
+                    .map_or(rep.hb.prev_log_index, |(i, _)| *i);
+                let commit = rep.hb.leader_commit.min(matched);
+                if commit > self.commit_index {
+                    self.commit_index = commit;
+                    self.perform();
+                }
+
+                self.accept(ep, rep.hb.round, matched);
+                self.reset_timeout();
+            }
+            // Too far behind for entries.
+            Message::InstallSnapshot(snap) => {
+                if snap.term < self.current_term {
+                    self.reject(ep, 0);
+                } else {
+                    self.step_down(snap.term);
+                    self.leader = Some(snap.leader_id);
+                    self.last_heard = Instant::now();
+                    self.install(snap);
+                    self.reset_timeout();
+                }
+            }
+            // Candidacy
+            Message::Campaign(c) => {
+                // Still hearing from a leader, which may hold a lease on that. Not even the
+                // term changes. Only a handover from the leader itself gets past this.
+                let recent = self.last_heard.elapsed() < self.election_timeout();
+                if !c.transfer && (recent || self.state == ServerState::Leader) {
+                    return;
+                }
+                // If we at newer term, reply false.
+                if c.term < self.current_term {
+                    self.reject(ep, 0);
+                    return;
+                }
+                // New term, nobody has our vote in it yet.
+                if c.term > self.current_term {
+                    self.step_down(c.term);
+                }
+                // One vote per term, and only for a log that has everything ours does.
+                let free = self.voted_for.is_none() || self.voted_for == Some(c.candidate_id);
+                if free && self.up_to_date(&c) {
+                    self.vote(ep, c);
+                } else {
+                    self.reject(ep, 0);
+                }
+            }
+
+            // Straight to the election, no pre-vote. The leader vouches for us.
+            Message::TimeoutNow(term) => {
+                if term == self.current_term
+                    && self.state == ServerState::Follower
+                {
+                    self.campaign(true);
+                }
+            }
+            Message::PreVote(ref c) => self.pre_vote(ep, c),
+            Message::PreVoteReply(res) => match self.state {
+                ServerState::PreCandidate(v) if res.success => {
+                    if !self.config.members.contains_key(&res.from)
+                        || !self.votes.insert(res.from)
+                    {
+                        return;
+                    }
+                    if v + 1 >= self.config.quorum() {
+                        self.campaign(false);
+                    } else {
+                        self.state = ServerState::PreCandidate(v + 1);
+                    }
+                }
+                _ if res.term > self.current_term => self.step_down(res.term),
+                _ => {}
+            },
+
+            Message::ServerReply(res) => {
+                match self.state {
+                    ServerState::Follower
+                    | ServerState::Learner
+                    | ServerState::PreCandidate(_) => {
+                        // println!("BAD.");
+                    }
+                    // Votes
+                    ServerState::Candidate(v) => {
+                        if res.success && res.term == self.current_term {
+                            // Only members get a say, once each.
+                            if !self.config.members.contains_key(&res.from)
+                                || !self.votes.insert(res.from)
+                            {
+                                return;
+                            }
+                            // Majority
+                            if v + 1 >= self.config.quorum() {
+                                self.crown();
+                            } else {
+                                self.state = ServerState::Candidate(v + 1);
+                            }
+                        } else if res.term > self.current_term {
+                            self.step_down(res.term);
+                        }
+                    }
+                    // Rejects
+                    ServerState::Leader => {
+                        if res.term > self.current_term {
+                            self.step_down(res.term);
+                        } else if res.term < self.current_term {
+                            // From back when we led an older term.
+                        } else {
+                            self.heard.insert(res.from);
+                            self.ack(res.from, res.round);
+                            if res.success {
+                                self.matched(res.from, res.match_index);
+                                let last = self.last_index();
+
+                                for i in (self.commit_index + 1..=last).rev()
+                                {
+                                    // Older terms only get committed along with one of ours.
+                                    if self.term_at(i) != Some(self.current_term) {
+                                        break;
+                                    }
+                                    // We count too, if we're still in. Learners don't.
+                                    let mut count = self.is_member() as usize;
+                                    for (a, b) in self.progress.iter() {
+                                        if b.matched >= i && self.config.members.contains_key(a) {
+                                            count += 1;
+                                        }
+                                    }
+                                    if count >= self.config.quorum() {
+                                        self.commit_index = i;
+                                        break;
+                                    }
+                                }
+
+                                if self.commit_index > self.last_applied {
+                                    self.perform();
+                                }
+                                self.try_hand_over();
+                                // Room in the window now, maybe.
+                                if self.state == ServerState::Leader {
+                                    self.replicate(res.from);
+                                }
+                            } else {
+                                // Term matches, log does not.
+                                self.back_off(&res);
+                                self.replicate(res.from);
+                            }
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Timer `t` went off. Anything but its latest generation was cancelled or replaced
+    /// after the fact, and gets ignored.
+    pub(super) fn signal(&mut self, t: Timer, generation: usize) {
+        let alarm = self.alarm(t);
+        if generation != alarm.generation {
+            return;
+        }
+        alarm.id = None;
+        self.tick(t);
+    }
+
+    pub(super) fn tick(&mut self, t: Timer) {
+        match t {
+            Timer::Heartbeat => {
+                if self.state == ServerState::Leader {
+                    self.empty_decree();
+                }
+            }
+            Timer::Election => {
+                // println!("Campaign {id}");
+                if self.state == ServerState::Leader {
+                    self.check_quorum();
+                    self.abort_transfer();
+                    self.reset_timeout();
+                } else {
+                    self.pre_campaign();
+                }
+            }
+        }
+    }
+}
+
+/// Runs server `id` of the cluster in `settings`, on its address there, replicating an `S`.
+/// Term, vote and log are kept under `data_dir`, and picked back up from there after a restart.
+pub fn run<S: StateMachine>(id: usize, data_dir: &Path, settings: &RaftConfig) {
+    run_with::<S>(id, data_dir, settings, settings.initial());
 }
 
-pub fn run(id: usize, addr: SocketAddr) {
+/// Like `run`, starting out as `config` instead of the cluster in `settings`.
+///
+/// A server joining an existing cluster starts with an empty config. It sits tight until
+/// the leader adds it and sends it the log.
+pub fn run_with<S: StateMachine>(
+    id: usize,
+    data_dir: &Path,
+    settings: &RaftConfig,
+    config: Config,
+) {
     let params = Params::new();
-    let (handler, listener) = node::split::<Timer>();
-    handler.network().listen(Transport::Udp, addr).unwrap();
-    let peers = get_peers(id, handler.clone());
+    let storage = Storage::open(data_dir, id).unwrap();
+    let (handler, listener) = node::split::<Signal>();
+    handler.network().listen(Transport::Udp, settings.addr(id)).unwrap();
 
-    let mut server = Server::new(id, peers, handler);
+    let mut server = Server::<S>::new(id, None, config, settings.clone(), handler, storage);
     // println!("Server {id} up.");
     let mut nt = listener.for_each_async(move |event| {
         match event {
@@ -256,212 +1305,875 @@ pub fn run(id: usize, addr: SocketAddr) {
                         // println!("Raft server {id} Disconnected from {ep}.");
                     }
                     NetEvent::Message(ep, buf) => {
-                        let msg = from_slice::<Message>(&buf);
+                        let msg = from_slice::<Message<S::Op>>(&buf);
                         if let Ok(msg) = msg {
-                            match msg {
-                                // If leader, decree. Else, redirect to leader.
-                                Message::Request(ref cmd) => {
-                                    // dbg!(&msg);
-                                    match server.state {
-                                        ServerState::Follower => {
-                                            if let Some(l) = server.voted_for {
-                                                // dbg!(id, l);
-                                                let leader = server.peers[&l];
-                                                server.handler.network().send(leader, &buf);
-                                            } else {
-                                                server.pending.push(msg);
-                                            }
-                                        },
-                                        ServerState::Candidate(_) => {
-                                            server.pending.push(msg);
-                                        },
-                                        ServerState::Leader => {
-                                            server.log.push(Log {
-                                                term: server.current_term,
-                                                command: Some(cmd.clone()),
-                                            });
-                                            server.decree();
-                                        },
-                                    }
-                                }
-
-                                // A server can never receive a response.
-                                // The leader responds to the client directly.
-                                // The client socket address is contained in the command.
-                                Message::Response(_) => unreachable!(),
-                                // Add to log
-                                Message::Heartbeat(rep) => {
-                                    // println!("HB, {}", rep.entries.len());
-                                    // Old leader
-                                    if rep.hb.term < server.current_term {
-                                        // println!("{}@{} Rejected {}@{}", id, server.current_term, rep.hb.leader_id, rep.hb.term);
-                                        server.reject(ep);
-                                    } else if server.log.len() - 1 < rep.hb.prev_log_index // Old log, send previous stuff also
-                                    || server.log[rep.hb.prev_log_index].term != rep.hb.prev_log_term // Log conflict, send previous stuff also
-                                    {
-                                        // So that pending messages are not lost.
-                                        server.current_term = rep.hb.term;
-                                        server.voted_for = Some(rep.hb.leader_id);
-                                        // println!("{} unmerge {}", id, rep.hb.leader_id);
-                                        server.reject(ep);
-                                    } else {
-                                        // println!("{} Accepted {}", id, rep.hb.leader_id);
-                                        server.current_term = rep.hb.term;
-                                        if let Some(t) = server.current_timer {
-                                            server.handler.signals().cancel_timer(t);
-                                        }
-                                        server.state = ServerState::Follower;
-                                        server.voted_for = Some(rep.hb.leader_id);
-                                        for (i, l) in rep.entries.iter() {
-                                            if *i < server.log.len() {
-                                                // Override
-                                                server.log[*i] = l.clone();
-                                            } else {
-                                                // New
-                                                server.log.push(l.clone());
-                                            }
-                                        }
-                                        
-                                        while !server.pending.is_empty() {
-                                            let msg = server.pending.pop().unwrap();
-                                            let leader = server.peers[&server.voted_for.unwrap()];
-                                            server
-                                                .handler
-                                                .network()
-                                                .send(leader, &to_vec(&msg).unwrap());
-                                        }
-                                    }
-
-                                    if rep.entries.len() > 0 {
-                                        // dbg!(&server.log);
-                                    }
-    
-                                    if rep.hb.leader_commit > server.commit_index {
-                                        server.commit_index =
-                                            rep.hb.leader_commit.min(server.log.len() - 1);
-                                        if server.commit_index > server.last_applied {
-                                            // perform
-                                            server.perform();
-                                        }
-                                    }
-    
-                                    server.accept(ep);
-                                    server.reset_timeout();
-                                }
-                                // Candidacy
-                                Message::Campaign(c) => {
-                                    // Somehow, we don't need to check ServerState?
-    
-                                    // If we at newer term, reply false.
-                                    if c.term < server.current_term {
-                                        server.reject(ep);
-                                    }
-                                    // Ambiguity here. Should we abstain?
-                                    else if c.term > server.current_term
-                                        || c.last_log_index >= server.log.len() - 1
-                                    {
-                                        // Can be optimised, not doing it.
-                                        if let Some(s) = server.voted_for {
-                                            if s == c.candidate_id {
-                                                server.vote(ep, c);
-                                            }
-                                        } else if server.voted_for.is_none() {
-                                            server.vote(ep, c);
-                                        }
-                                    }
-                                }
-    
-                                Message::ServerReply(res) => {
-                                    match server.state {
-                                        ServerState::Follower => {
-                                            // println!("BAD.");
-                                        }
-                                        // Votes
-                                        ServerState::Candidate(v) => {
-                                            if res.success {
-                                                // Majority
-                                                if v > (server.peers.len() + 1) / 2 {
-                                                    server.crown();
-                                                } else {
-                                                    server.state = ServerState::Candidate(v + 1);
-                                                }
-                                            } else {
-                                                server.current_term = res.term;
-                                                server.state = ServerState::Follower;
-                                                server.voted_for = None
-                                            }
-                                        }
-                                        // Rejects
-                                        ServerState::Leader => {
-                                            if res.term > server.current_term {
-                                                server.current_term = res.term;
-                                                server.state = ServerState::Follower;
-                                                server.voted_for = None;
-                                                // todo!()
-                                            } else {
-                                                if res.success {
-                                                    // TODO: Verify.
-                                                    server
-                                                        .match_index
-                                                        .insert(res.from, server.log.len() - 1);
-                                                    server
-                                                        .next_index
-                                                        .insert(res.from, server.log.len());
-    
-                                                    for i in (server.commit_index + 1..server.log.len())
-                                                        .rev()
-                                                    {
-                                                        let mut count = 1;
-                                                        for (_a, b) in server.match_index.iter() {
-                                                            if *b >= i {
-                                                                count += 1;
-                                                            }
-                                                        }
-                                                        if count > (server.peers.len() + 1) / 2 {
-                                                            server.commit_index = i;
-                                                            break;
-                                                        }
-                                                    }
-    
-                                                    if server.commit_index > server.last_applied {
-                                                        server.perform();
-                                                    }
-                                                } else {
-                                                    // Term matches, log does not.
-                                                    let u = server.next_index.get_mut(&res.from).unwrap();
-                                                    if *u > 0 {
-                                                        *u -= 1;
-                                                    }
-                                                    server.decree();
-                                                }
-                                            }
-                                        }
-                                    }
-                                }
-                            }
+                            server.handle(ep, msg);
+                        }
+                        // A follower may not see another timer go off for as long as the
+                        // leader keeps in touch.
+                        if server.last_index() >= params.k {
+                            server.handler.stop();
                         }
                     }
                 }
             }
-            NodeEvent::Signal(t) => {
-                if server.log.len() > params.k {
+            NodeEvent::Signal((_, t, generation)) => {
+                if server.last_index() >= params.k {
                     // dbg!(server.log.len(), params.k);
                     server.handler.stop();
                     return;
                 }
-                match t {
-                Timer::Heartbeat => {
-                    if server.state == ServerState::Leader {
-                        server.empty_decree();
+                server.signal(t, generation);
+            }
+        }
+    });
+
+    nt.wait();
+}
+
+/// Whole clusters in one thread, with the network replaced by a bag of messages that
+/// go out in any order, twice, or not at all. Servers crash and come back from disk.
+/// After every step, the properties from Figure 3 of the Raft paper have to hold.
+#[cfg(test)]
+mod tests {
+    use std::{cell::RefCell, net::SocketAddr, path::PathBuf};
+
+    use message_io::node::NodeListener;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    use super::*;
+    use crate::{
+        kv::{KvOp, KvStore},
+        MAX_DATAGRAM,
+    };
+
+    const SERVERS: usize = 5;
+    const SEEDS: u64 = 20;
+    const STEPS: usize = 2000;
+    const CLIENT: ([u8; 4], u16) = ([127, 0, 0, 1], 18999);
+
+    thread_local! {
+        /// Everything sent on this thread and not picked up yet: from, to, message.
+        /// Each test runs on a thread of its own.
+        static OUTBOX: RefCell<Vec<(usize, Endpoint, Vec<u8>)>> = const { RefCell::new(vec![]) };
+    }
+
+    struct Sim {
+        servers: Vec<Option<Server<KvStore>>>,
+        /// One per server, kept across crashes. Tearing a node down takes a while.
+        nodes: Vec<(NodeHandler<Signal>, NodeListener<Signal>)>,
+        config: Config,
+        dir: PathBuf,
+        /// In flight: from, to, message.
+        net: Vec<(usize, usize, Vec<u8>)>,
+        rng: StdRng,
+        /// Who led each term.
+        leaders: BTreeMap<usize, usize>,
+        /// Every committed entry by index, with the term of whoever saw it committed first.
+        committed: BTreeMap<usize, (Log<KvOp>, usize)>,
+        op_id: usize,
+    }
+
+    impl Sim {
+        fn new(seed: u64) -> Self {
+            let dir = std::env::temp_dir().join(format!("raft-sim-{}-{seed}", std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            let config = Config {
+                members: (0..SERVERS)
+                    .map(|i| (i, SocketAddr::from(([127, 0, 0, 1], 19000 + i as u16))))
+                    .collect(),
+                ..Default::default()
+            };
+            let mut sim = Self {
+                servers: vec![],
+                nodes: (0..SERVERS).map(|_| node::split()).collect(),
+                config,
+                dir,
+                net: vec![],
+                rng: StdRng::seed_from_u64(seed),
+                leaders: BTreeMap::new(),
+                committed: BTreeMap::new(),
+                op_id: 0,
+            };
+            sim.servers = (0..SERVERS).map(|i| Some(sim.boot(i))).collect();
+            sim
+        }
+
+        /// Starts `id` from whatever it saved last time.
+        fn boot(&self, id: usize) -> Server<KvStore> {
+            let storage = Storage::open(&self.dir, id).unwrap();
+            let (config, settings) = (self.config.clone(), RaftConfig::default());
+            let mut s = Server::new(id, None, config, settings, self.nodes[id].0.clone(), storage);
+            s.wire = Box::new(move |ep, buf| {
+                OUTBOX.with_borrow_mut(|out| out.push((id, ep, buf.to_vec())));
+            });
+            s
+        }
+
+        fn live(&self) -> impl Iterator<Item = &Server<KvStore>> {
+            self.servers.iter().flatten()
+        }
+
+        /// Moves everything sent since last time onto the network. Client responses go nowhere.
+        fn collect(&mut self) {
+            for (from, ep, buf) in OUTBOX.take() {
+                let to = self.config.members.iter().find(|(_, a)| **a == ep.addr());
+                if let Some((to, _)) = to {
+                    self.net.push((from, *to, buf));
+                }
+            }
+        }
+
+        fn deliver(&mut self) {
+            if self.net.is_empty() {
+                return;
+            }
+            let at = self.rng.gen_range(0..self.net.len());
+            let (from, to, buf) = self.net.swap_remove(at);
+            let Some(s) = self.servers[to].as_mut() else { return };
+            let Some(ep) = s.peers.get(&from).copied() else { return };
+            s.handle(ep, from_slice(&buf).unwrap());
+        }
+
+        /// Lets an election timeout's worth of time go by, everywhere.
+        fn age(&mut self) {
+            let then = Instant::now() - Duration::from_secs(1);
+            for s in self.servers.iter_mut().flatten() {
+                s.last_heard = then;
+                s.quorum_checked = then;
+            }
+        }
+
+        fn step(&mut self) {
+            let id = self.rng.gen_range(0..SERVERS);
+            match self.rng.gen_range(0..100) {
+                0..=49 => self.deliver(),
+                50..=56 if !self.net.is_empty() => {
+                    let at = self.rng.gen_range(0..self.net.len());
+                    self.net.swap_remove(at);
+                }
+                57..=59 if !self.net.is_empty() => {
+                    let at = self.rng.gen_range(0..self.net.len());
+                    self.net.push(self.net[at].clone());
+                }
+                60..=65 => {
+                    self.age();
+                    if let Some(s) = self.servers[id].as_mut() {
+                        s.tick(Timer::Election);
                     }
                 }
-                Timer::Election => {
-                    // println!("Campaign {id}");
-                    server.campaign();
+                66..=81 => {
+                    if let Some(s) = self.servers[id].as_mut() {
+                        s.tick(Timer::Heartbeat);
+                    }
                 }
-            }},
+                82..=91 => {
+                    self.op_id += 1;
+                    let cmd = Command {
+                        client: SocketAddr::from(CLIENT),
+                        session: 0,
+                        op_id: self.op_id,
+                        op: KvOp::Put {
+                            key: format!("k{}", self.op_id % 7),
+                            value: self.op_id.to_string(),
+                        },
+                    };
+                    if let Some(s) = self.servers[id].as_mut() {
+                        let ep = *s.peers.values().next().unwrap();
+                        s.handle(ep, Message::Request(cmd));
+                    }
+                }
+                92..=95 => self.servers[id] = None,
+                _ => {
+                    if self.servers[id].is_none() {
+                        self.servers[id] = Some(self.boot(id));
+                    }
+                }
+            }
+            self.collect();
         }
-    });
 
-    nt.wait();
+        fn check(&mut self) {
+            // Election Safety: at most one leader per term.
+            for s in self.servers.iter().flatten().filter(|s| s.state == ServerState::Leader) {
+                let leader = *self.leaders.entry(s.current_term).or_insert(s.id);
+                assert_eq!(leader, s.id, "two leaders in term {}", s.current_term);
+            }
+
+            // Log Matching: same index and term means the same log up to there.
+            let live = self.live().collect::<Vec<_>>();
+            for (a, b) in live.iter().flat_map(|a| live.iter().map(move |b| (a, b))) {
+                let lo = a.offset.max(b.offset);
+                let hi = a.last_index().min(b.last_index());
+                let Some(i) = (lo..=hi).rev().find(|i| a.term_at(*i) == b.term_at(*i)) else {
+                    continue;
+                };
+                assert_eq!(
+                    a.log[lo - a.offset..=i - a.offset],
+                    b.log[lo - b.offset..=i - b.offset],
+                    "servers {} and {} agree on {i} but not before",
+                    a.id,
+                    b.id
+                );
+            }
+
+            // Committed entries never change.
+            let mut seen = vec![];
+            for s in self.live() {
+                for i in s.offset + 1..=s.commit_index {
+                    seen.push((i, s.log[i - s.offset].clone(), s.current_term));
+                }
+            }
+            for (i, log, term) in seen {
+                let (first, _) = self.committed.entry(i).or_insert((log.clone(), term));
+                assert_eq!(*first, log, "entry {i} changed after it was committed");
+            }
+
+            // Leader Completeness: leaders of later terms have everything committed.
+            for s in self.live().filter(|s| s.state == ServerState::Leader) {
+                for (i, (log, term)) in self.committed.range(s.offset + 1..) {
+                    if *term < s.current_term {
+                        assert!(*i <= s.last_index(), "leader {} is missing {i}", s.id);
+                        assert_eq!(s.log[i - s.offset], *log, "leader {} differs at {i}", s.id);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Hands what `from` sent since last time to those of `to` it was meant for.
+    /// The rest is lost.
+    fn pass(sim: &mut Sim, from: usize, to: &[usize]) {
+        let out = sent(sim.servers[from].as_ref().unwrap());
+        for (ep, msg) in out {
+            let Some((id, _)) = sim.config.members.iter().find(|(_, a)| **a == ep.addr()) else {
+                continue;
+            };
+            if to.contains(id) {
+                let s = sim.servers[*id].as_mut().unwrap();
+                s.handle(s.peers[&from], msg);
+            }
+        }
+    }
+
+    /// Everything `s` sent since last time, still encoded.
+    fn raw(s: &Server<KvStore>) -> Vec<(Endpoint, Vec<u8>)> {
+        OUTBOX.with_borrow_mut(|out| {
+            let (mine, rest) = out.drain(..).partition(|(from, ..)| *from == s.id);
+            *out = rest;
+            mine.into_iter().map(|(_, ep, buf)| (ep, buf)).collect::<Vec<_>>()
+        })
+    }
+
+    /// Everything `s` sent since last time.
+    fn sent(s: &Server<KvStore>) -> Vec<(Endpoint, Message<KvOp>)> {
+        raw(s).into_iter().map(|(ep, buf)| (ep, from_slice(&buf).unwrap())).collect()
+    }
+
+    impl Drop for Sim {
+        fn drop(&mut self) {
+            self.servers.clear();
+            let _ = std::fs::remove_dir_all(&self.dir);
+        }
+    }
+
+    #[test]
+    fn randomized_runs_stay_safe() {
+        let mut committed = 0;
+        for seed in 0..SEEDS {
+            let mut sim = Sim::new(seed);
+            for _ in 0..STEPS {
+                sim.step();
+                sim.check();
+            }
+            committed += sim.committed.len();
+        }
+        // Or there was nothing to check.
+        assert!(committed > 0);
+    }
+
+    /// A longer log loses to a log with a newer last term.
+    #[test]
+    fn votes_need_an_up_to_date_log() {
+        let mut sim = Sim::new(100);
+        let s = sim.servers[0].as_mut().unwrap();
+        s.current_term = 3;
+        s.log.push(Log {
+            term: 3,
+            command: None,
+            config: None,
+        });
+        let stale = Campaign {
+            term: 4,
+            candidate_id: 1,
+            last_log_index: 5,
+            last_log_term: 2,
+            transfer: false,
+        };
+        s.last_heard = Instant::now() - Duration::from_secs(1);
+        s.handle(s.peers[&1], Message::Campaign(stale));
+        let Some((_, Message::ServerReply(rep))) = sent(s).pop() else { panic!() };
+        assert!(!rep.success);
+        assert_eq!((s.current_term, s.voted_for), (4, None));
+    }
+
+    /// An entry from an old term on a majority isn't committed until one from ours is.
+    #[test]
+    fn old_terms_commit_with_the_current_one() {
+        let mut sim = Sim::new(101);
+        let s = sim.servers[0].as_mut().unwrap();
+        s.current_term = 2;
+        s.state = ServerState::Leader;
+        for term in [1, 2] {
+            s.log.push(Log {
+                term,
+                command: None,
+                config: None,
+            });
+        }
+        let reply = |from, match_index| {
+            Message::ServerReply(Reply {
+                from,
+                success: true,
+                term: 2,
+                match_index,
+                ..Default::default()
+            })
+        };
+        s.handle(s.peers[&1], reply(1, 1));
+        s.handle(s.peers[&2], reply(2, 1));
+        assert_eq!(s.commit_index, 0);
+        s.handle(s.peers[&1], reply(1, 2));
+        assert_eq!(s.commit_index, 0);
+        s.handle(s.peers[&2], reply(2, 2));
+        assert_eq!(s.commit_index, 2);
+    }
+
+    /// A pre-vote changes nobody's term, and nobody who still hears from a leader grants one.
+    #[test]
+    fn pre_votes_leave_terms_alone() {
+        let mut sim = Sim::new(105);
+        sim.servers[0].as_mut().unwrap().tick(Timer::Election);
+        let s = sim.servers[0].as_ref().unwrap();
+        assert_eq!((s.state, s.current_term, s.voted_for), (ServerState::PreCandidate(1), 0, None));
+        let asks = sent(s);
+        assert_eq!(asks.len(), SERVERS - 1);
+        let Message::PreVote(c) = asks[0].1.clone() else { panic!() };
+        assert_eq!(c.term, 1);
+
+        // Server 1 heard from a leader just now. Servers 2 and 3 haven't in a while.
+        let mut replies = vec![];
+        for id in 1..4 {
+            let p = sim.servers[id].as_mut().unwrap();
+            if id > 1 {
+                p.last_heard = Instant::now() - Duration::from_secs(1);
+            }
+            p.handle(p.peers[&0], Message::PreVote(c.clone()));
+            assert_eq!((p.current_term, p.voted_for), (0, None));
+            let (_, reply) = sent(p).pop().unwrap();
+            let Message::PreVoteReply(r) = reply.clone() else { panic!() };
+            assert_eq!(r.success, id > 1);
+            replies.push((id, reply));
+        }
+
+        let s = sim.servers[0].as_mut().unwrap();
+        for (id, reply) in replies {
+            s.handle(s.peers[&id], reply);
+        }
+        // Now the real thing.
+        assert_eq!((s.state, s.current_term, s.voted_for), (ServerState::Candidate(1), 1, Some(0)));
+    }
+
+    /// A leader that doesn't hear from a majority within an election timeout steps down.
+    #[test]
+    fn leaders_step_down_without_a_quorum() {
+        let mut sim = Sim::new(106);
+        let s = sim.servers[0].as_mut().unwrap();
+        s.current_term = 1;
+        s.crown();
+        let heard = |from| {
+            Message::ServerReply(Reply { from, success: true, term: 1, ..Default::default() })
+        };
+        for from in [1, 2] {
+            s.handle(s.peers[&from], heard(from));
+        }
+        s.quorum_checked = Instant::now() - Duration::from_secs(1);
+        s.tick(Timer::Election);
+        assert_eq!(s.state, ServerState::Leader);
+
+        // Only one answers this time. With us that's two of five.
+        s.handle(s.peers[&3], heard(3));
+        s.quorum_checked = Instant::now() - Duration::from_secs(1);
+        s.tick(Timer::Election);
+        assert_eq!((s.state, s.leader, s.current_term), (ServerState::Follower, None, 1));
+        assert!(s.heartbeat.id.is_none());
+    }
+
+    fn put(op_id: usize) -> Command<KvOp> {
+        let op = KvOp::Put { key: "k".into(), value: op_id.to_string() };
+        Command { client: SocketAddr::from(CLIENT), session: 0, op_id, op }
+    }
+
+    /// Leadership goes over once the target has everything. Requests wait meanwhile,
+    /// and go in after all if it never takes over.
+    #[test]
+    fn transfers_wait_for_the_target_to_catch_up() {
+        let mut sim = Sim::new(107);
+        let s = sim.servers[0].as_mut().unwrap();
+        s.current_term = 1;
+        s.crown();
+        s.handle(s.peers[&1], Message::Transfer(1));
+        assert!(s.transfer.is_some());
+        s.handle(s.peers[&2], Message::Request(put(1)));
+        assert_eq!((s.last_index(), s.pending.len()), (1, 1));
+        let timeout_now = |msgs: Vec<(Endpoint, Message<KvOp>)>| {
+            msgs.into_iter().find_map(|(_, m)| match m {
+                Message::TimeoutNow(t) => Some(t),
+                _ => None,
+            })
+        };
+        assert_eq!(timeout_now(sent(s)), None);
+
+        let caught_up = Reply {
+            from: 1,
+            success: true,
+            term: 1,
+            match_index: 1,
+            ..Default::default()
+        };
+        s.handle(s.peers[&1], Message::ServerReply(caught_up));
+        assert_eq!(timeout_now(sent(s)), Some(1));
+
+        // Straight to a campaign, no pre-vote.
+        let t = sim.servers[1].as_mut().unwrap();
+        t.current_term = 1;
+        t.handle(t.peers[&0], Message::TimeoutNow(1));
+        assert_eq!((t.state, t.current_term), (ServerState::Candidate(1), 2));
+
+        // Never heard back. The old leader carries on with what came in meanwhile.
+        let s = sim.servers[0].as_mut().unwrap();
+        s.transfer = Some((1, Instant::now() - Duration::from_secs(1)));
+        s.tick(Timer::Election);
+        assert!(s.transfer.is_none());
+        assert!(s.pending.is_empty());
+        assert_eq!(s.last_index(), 2);
+    }
+
+    /// A follower that disagrees says which term it has there, and where that starts.
+    /// The leader skips the whole term, so one retry is enough either way.
+    #[test]
+    fn conflicts_back_off_a_term_at_a_time() {
+        let cases: [(&[usize], &[usize], _); 3] = [
+            // Nothing from term 2 on the leader. All of it goes.
+            (&[1, 1, 3, 3], &[1, 1, 2, 2, 2, 2, 2], (Some(2), 3)),
+            // Some of term 2 is on both. Picks up right after the leader's last one.
+            (&[1, 2, 2, 3], &[1, 2, 2, 2, 2, 2], (Some(2), 2)),
+            // Too short, nothing there to disagree with.
+            (&[1, 1, 3, 3], &[1], (None, 2)),
+        ];
+        for (leader, follower, hint) in cases {
+            let mut sim = Sim::new(109);
+            for (id, terms) in [(0, leader), (1, follower)] {
+                let s = sim.servers[id].as_mut().unwrap();
+                s.current_term = 3;
+                for &term in terms {
+                    s.log.push(Log { term, command: None, config: None });
+                }
+            }
+            let s = sim.servers[0].as_mut().unwrap();
+            s.current_term = 4;
+            s.crown();
+
+            let mut rounds = 0;
+            loop {
+                let s = sim.servers[0].as_mut().unwrap();
+                let to = s.peers[&1];
+                let out = sent(s).into_iter().filter(|(ep, _)| *ep == to).collect::<Vec<_>>();
+                if out.is_empty() {
+                    break;
+                }
+                rounds += 1;
+                let f = sim.servers[1].as_mut().unwrap();
+                for (_, msg) in out {
+                    f.handle(f.peers[&0], msg);
+                }
+                let replies = sent(f);
+                if rounds == 1 {
+                    let Message::ServerReply(r) = &replies[0].1 else { panic!() };
+                    assert_eq!((r.conflict_term, r.conflict_index), hint);
+                }
+                let s = sim.servers[0].as_mut().unwrap();
+                for (_, msg) in replies {
+                    s.handle(s.peers[&1], msg);
+                }
+            }
+            let (s, f) = (sim.servers[0].as_ref().unwrap(), sim.servers[1].as_ref().unwrap());
+            assert_eq!(rounds, 2);
+            assert_eq!(f.log, s.log);
+            assert_eq!(s.progress[&1].matched, s.last_index());
+        }
+    }
+
+    /// Learners get the log, but their votes and acks count for nothing, and they never run.
+    #[test]
+    fn learners_stay_out_of_majorities() {
+        let mut sim = Sim::new(110);
+        sim.servers.clear();
+        let learners = sim.config.members.split_off(&3);
+        sim.config.learners = learners;
+        sim.servers = (0..SERVERS).map(|i| Some(sim.boot(i))).collect();
+
+        let l = sim.servers[3].as_mut().unwrap();
+        assert_eq!(l.state, ServerState::Learner);
+        l.last_heard = Instant::now() - Duration::from_secs(1);
+        l.tick(Timer::Election);
+        assert_eq!((l.state, l.current_term), (ServerState::Learner, 0));
+        assert!(sent(l).is_empty());
+
+        // Three members, so it takes two. Learners don't make up the difference.
+        let s = sim.servers[0].as_mut().unwrap();
+        s.tick(Timer::Election);
+        let yes = |from| {
+            Message::PreVoteReply(Reply { from, success: true, term: 1, ..Default::default() })
+        };
+        for from in [3, 4] {
+            s.handle(s.peers[&from], yes(from));
+        }
+        assert_eq!(s.state, ServerState::PreCandidate(1));
+        s.handle(s.peers[&1], yes(1));
+        assert_eq!(s.state, ServerState::Candidate(1));
+        let vote = |from| {
+            Message::ServerReply(Reply { from, success: true, term: 1, ..Default::default() })
+        };
+        for from in [3, 4] {
+            s.handle(s.peers[&from], vote(from));
+        }
+        assert_eq!(s.state, ServerState::Candidate(1));
+        s.handle(s.peers[&2], vote(2));
+        assert_eq!(s.state, ServerState::Leader);
+
+        let acked = |from| {
+            Message::ServerReply(Reply {
+                from,
+                success: true,
+                term: 1,
+                match_index: 1,
+                ..Default::default()
+            })
+        };
+        for from in [3, 4] {
+            s.handle(s.peers[&from], acked(from));
+        }
+        assert_eq!(s.commit_index, 0);
+        // Heard from both learners, but no member. Not enough to stay on.
+        s.quorum_checked = Instant::now() - Duration::from_secs(1);
+        s.tick(Timer::Election);
+        assert_eq!(s.state, ServerState::Follower);
+    }
+
+    /// Requests held during the election go in once it's won, after the new leader's no-op.
+    #[test]
+    fn new_leaders_take_up_held_requests() {
+        let mut sim = Sim::new(111);
+        let s = sim.servers[0].as_mut().unwrap();
+        s.tick(Timer::Election);
+        let get = Command { op: KvOp::Get { key: "k".into() }, ..put(3) };
+        for cmd in [put(1), put(2), get] {
+            s.handle(s.peers[&1], Message::Request(cmd));
+        }
+        assert_eq!((s.last_index(), s.pending.len()), (0, 3));
+
+        s.current_term = 1;
+        s.crown();
+        assert!(s.pending.is_empty());
+        let ops = s.log[1..].iter().map(|l| l.command.as_ref().map(|c| c.op_id));
+        assert_eq!(ops.collect::<Vec<_>>(), vec![None, Some(1), Some(2)]);
+        // The read waits on a heartbeat round, not the log.
+        assert_eq!(s.reads.len(), 1);
+    }
+
+    /// No more than `max_inflight` batches out to a follower at once, each within `max_batch`
+    /// entries and about `max_batch_bytes`. An answer makes room for the next ones.
+    #[test]
+    fn batches_stay_within_the_limits() {
+        let mut sim = Sim::new(112);
+        let s = sim.servers[0].as_mut().unwrap();
+        (s.settings.max_inflight, s.settings.max_batch) = (2, 3);
+        s.current_term = 1;
+        s.crown();
+        let to = s.peers[&1];
+        let batches = |s: &Server<KvStore>| {
+            let out = sent(s).into_iter().filter(|(ep, _)| *ep == to);
+            let entries = out.map(|(_, m)| match m {
+                Message::Heartbeat(r) => r.entries.iter().map(|(i, _)| *i).collect::<Vec<_>>(),
+                _ => panic!(),
+            });
+            entries.collect::<Vec<_>>()
+        };
+        assert_eq!(batches(s), vec![vec![1]]);
+        let entry = |len: usize| {
+            let op = KvOp::Put { key: "k".into(), value: "x".repeat(len) };
+            Log { term: 1, command: Some(Command { op, ..put(0) }), config: None }
+        };
+
+        // The no-op is still out, so one more fits.
+        for _ in 0..10 {
+            s.log.push(entry(1));
+        }
+        s.replicate(1);
+        assert_eq!(batches(s), vec![vec![2, 3, 4]]);
+        let acked = |match_index| {
+            Message::ServerReply(Reply {
+                from: 1,
+                success: true,
+                term: 1,
+                match_index,
+                ..Default::default()
+            })
+        };
+        s.handle(to, acked(4));
+        assert_eq!(batches(s), vec![vec![5, 6, 7], vec![8, 9, 10]]);
+        s.handle(to, acked(10));
+        assert_eq!(batches(s), vec![vec![11]]);
+        s.handle(to, acked(11));
+
+        // Two of these fit in a batch.
+        s.settings.max_batch_bytes = 2 * to_vec(&(12, entry(100))).unwrap().len();
+        for _ in 0..3 {
+            s.log.push(entry(100));
+        }
+        s.replicate(1);
+        assert_eq!(batches(s), vec![vec![12, 13], vec![14]]);
+        s.handle(to, acked(14));
+
+        // One that could never go out doesn't go in either.
+        let op = KvOp::Put { key: "k".into(), value: "x".repeat(500) };
+        s.handle(s.peers[&2], Message::Request(Command { op, ..put(15) }));
+        assert_eq!(s.last_index(), 14);
+        let out = sent(s);
+        assert!(matches!(&out[..], [(_, Message::TooBig(cmd))] if cmd.op_id == 15));
+    }
+
+    /// Held messages stop at `pending_max`. Clients past that are told to try again.
+    #[test]
+    fn held_requests_are_capped() {
+        let mut sim = Sim::new(113);
+        let s = sim.servers[0].as_mut().unwrap();
+        s.settings.pending_max = 2;
+        s.tick(Timer::Election);
+        sent(s);
+        for op_id in 1..=3 {
+            s.handle(s.peers[&1], Message::Request(put(op_id)));
+        }
+        assert_eq!(s.pending.len(), 2);
+        let Some((_, Message::NotLeader { cmd, hint })) = sent(s).pop() else { panic!() };
+        assert_eq!((cmd.op_id, hint), (3, None));
+    }
+
+    /// With `lease_reads` on, a leader a majority answered recently serves reads straight away.
+    /// Otherwise every read waits on a heartbeat round of its own.
+    #[test]
+    fn lease_reads_skip_the_round() {
+        for lease_reads in [false, true] {
+            let mut sim = Sim::new(108);
+            let s = sim.servers[0].as_mut().unwrap();
+            s.settings.lease_reads = lease_reads;
+            s.current_term = 1;
+            s.crown();
+            for from in [1, 2] {
+                let reply = Reply {
+                    from,
+                    success: true,
+                    term: 1,
+                    round: s.round,
+                    match_index: 1,
+                    ..Default::default()
+                };
+                s.handle(s.peers[&from], Message::ServerReply(reply));
+            }
+            assert_eq!(s.commit_index, 1);
+            sent(s);
+
+            let get = Command { op: KvOp::Get { key: "k".into() }, ..put(1) };
+            s.handle(s.peers[&1], Message::Request(get));
+            let out = sent(s);
+            let answered = out.iter().any(|(_, m)| matches!(m, Message::Response(..)));
+            let rounds = out.iter().filter(|(_, m)| matches!(m, Message::Heartbeat(_))).count();
+            assert_eq!(answered, lease_reads);
+            assert_eq!(rounds, if lease_reads { 0 } else { SERVERS - 1 });
+            assert_eq!(s.reads.len(), !lease_reads as usize);
+        }
+    }
+
+    /// A follower that granted a pre-vote and then heard from the leader again won't vote
+    /// until an election timeout later, by when the lease it just extended has run out.
+    /// Only a `TimeoutNow` gets around that, and it ends leases for the rest of the term.
+    #[test]
+    fn votes_wait_out_the_lease() {
+        let mut sim = Sim::new(114);
+        for s in sim.servers.iter_mut().flatten() {
+            s.current_term = 1;
+            s.settings.lease_reads = true;
+        }
+        sim.servers[0].as_mut().unwrap().crown();
+        pass(&mut sim, 0, &[1, 2, 3, 4]);
+        for id in 1..SERVERS {
+            pass(&mut sim, id, &[0]);
+        }
+        let s = sim.servers[0].as_mut().unwrap();
+        assert_eq!(s.commit_index, 1);
+        assert!(s.holds_lease());
+
+        // A while later, 4 stops hearing from the leader. The others give it a pre-vote.
+        sim.age();
+        sim.servers[0].as_mut().unwrap().lease_until = Some(Instant::now());
+        sim.servers[4].as_mut().unwrap().tick(Timer::Election);
+        pass(&mut sim, 4, &[1, 2, 3]);
+        // The leader's next round gets through to 1 and 2 before 4 runs.
+        sim.servers[0].as_mut().unwrap().tick(Timer::Heartbeat);
+        pass(&mut sim, 0, &[1, 2]);
+        pass(&mut sim, 1, &[0, 4]);
+        pass(&mut sim, 2, &[0, 4]);
+        pass(&mut sim, 3, &[4]);
+        let s = sim.servers[0].as_mut().unwrap();
+        assert!(s.holds_lease());
+
+        let c = sim.servers[4].as_ref().unwrap();
+        assert_eq!((c.state, c.current_term), (ServerState::Candidate(1), 2));
+        pass(&mut sim, 4, &[1, 2, 3]);
+        for id in [1, 2] {
+            let f = sim.servers[id].as_ref().unwrap();
+            assert_eq!((f.current_term, f.voted_for), (1, None));
+            assert!(sent(f).is_empty());
+        }
+        pass(&mut sim, 3, &[4]);
+        let c = sim.servers[4].as_ref().unwrap();
+        assert_eq!(c.state, ServerState::Candidate(2));
+
+        // A handover campaign goes through anyway. So the leader stops trusting its lease
+        // the moment it sends the `TimeoutNow`, even if the handover is called off.
+        let s = sim.servers[0].as_mut().unwrap();
+        s.handle(s.peers[&1], Message::Transfer(1));
+        let late = sent(s).into_iter().find(|(_, m)| matches!(m, Message::TimeoutNow(_)));
+        assert!(s.handed_over && !s.holds_lease());
+        s.transfer = Some((1, Instant::now() - Duration::from_secs(1)));
+        s.tick(Timer::Election);
+        assert!(s.transfer.is_none());
+        s.tick(Timer::Heartbeat);
+        pass(&mut sim, 0, &[2, 3]);
+        pass(&mut sim, 2, &[0]);
+        pass(&mut sim, 3, &[0]);
+        assert!(!sim.servers[0].as_ref().unwrap().holds_lease());
+
+        let t = sim.servers[1].as_mut().unwrap();
+        t.handle(t.peers[&0], late.unwrap().1);
+        assert_eq!((t.state, t.current_term), (ServerState::Candidate(1), 2));
+        // 3 already voted for 4 this term. The old leader gives up its own.
+        pass(&mut sim, 1, &[0, 2]);
+        pass(&mut sim, 0, &[1]);
+        pass(&mut sim, 2, &[1]);
+        assert_eq!(sim.servers[1].as_ref().unwrap().state, ServerState::Leader);
+        assert_eq!(sim.servers[0].as_ref().unwrap().state, ServerState::Follower);
+    }
+
+    /// A new leader makes no config change until something from its own term is committed.
+    #[test]
+    fn config_changes_wait_for_the_current_term() {
+        let mut sim = Sim::new(104);
+        let s = sim.servers[0].as_mut().unwrap();
+        s.current_term = 1;
+        s.crown();
+        let change = Change::Remove(4);
+        s.reconfigure(&change);
+        assert_eq!(s.config.members.len(), SERVERS);
+        assert_eq!(s.last_index(), 1);
+
+        s.commit_index = 1;
+        s.perform();
+        s.reconfigure(&change);
+        assert!(!s.config.members.contains_key(&4));
+        assert_eq!(s.config_index, 2);
+        // And the one after waits for that to commit.
+        s.reconfigure(&Change::Remove(3));
+        assert!(s.config.members.contains_key(&3));
+    }
+
+    /// A snapshot too big for one datagram goes out in pieces, and only a complete,
+    /// in-order set gets installed.
+    #[test]
+    fn snapshots_go_out_in_pieces() {
+        let mut sim = Sim::new(103);
+        let s = sim.servers[0].as_mut().unwrap();
+        s.settings.snapshot_every = 1;
+        s.current_term = 1;
+        s.crown();
+        for i in 0..300 {
+            let op = KvOp::Put { key: format!("k{i}"), value: "x".repeat(200) };
+            s.append(Command { op_id: i, op, ..put(0) });
+        }
+        s.commit_index = s.last_index();
+        s.perform();
+        assert_eq!(s.offset, s.last_index());
+        assert!(s.snapshot.len() > 65_536);
+
+        let to = s.peers[&1];
+        let pr = s.progress.get_mut(&1).unwrap();
+        (pr.next, pr.inflight) = (1, VecDeque::new());
+        raw(s);
+        s.replicate(1);
+        let pieces = raw(s)
+            .into_iter()
+            .filter(|(ep, _)| *ep == to)
+            .map(|(_, buf)| buf)
+            .collect::<Vec<_>>();
+        assert!(pieces.len() > 1);
+        assert!(pieces.iter().all(|buf| buf.len() <= MAX_DATAGRAM));
+        let (offset, snapshot) = (s.offset, s.snapshot.clone());
+
+        let f = sim.servers[1].as_mut().unwrap();
+        let from = f.peers[&0];
+        // One lost on the way. The rest can't be used.
+        for (i, buf) in pieces.iter().enumerate() {
+            if i != 3 {
+                f.handle(from, from_slice(buf).unwrap());
+            }
+        }
+        assert_eq!(f.offset, 0);
+        // The leader tries again.
+        for buf in pieces.iter() {
+            f.handle(from, from_slice(buf).unwrap());
+        }
+        assert_eq!((f.offset, f.last_applied), (offset, offset));
+        assert_eq!(f.snapshot, snapshot);
+        assert_eq!(f.rst.state().get("k299").map(String::len), Some(200));
+    }
+
+    /// A timer that was reset or stopped can still have a signal on its way. Only the latest
+    /// one counts, and a leader never has more than one heartbeat coming.
+    #[test]
+    fn stale_timer_signals_are_ignored() {
+        let mut sim = Sim::new(102);
+        let s = sim.servers[0].as_mut().unwrap();
+        let old = s.election.generation;
+        s.reset_timeout();
+        s.signal(Timer::Election, old);
+        assert_eq!(s.state, ServerState::Follower);
+        s.signal(Timer::Election, s.election.generation);
+        assert_eq!(s.state, ServerState::PreCandidate(1));
+
+        s.current_term = 1;
+        s.crown();
+        let first = s.heartbeat.id;
+        let generation = s.heartbeat.generation;
+        s.decree();
+        s.decree();
+        assert!(first.is_some());
+        assert_eq!((s.heartbeat.id, s.heartbeat.generation), (first, generation));
+
+        s.step_down(2);
+        assert!(s.heartbeat.id.is_none());
+        raw(s);
+        s.signal(Timer::Heartbeat, generation);
+        assert!(raw(s).is_empty());
+    }
 }
@@ -0,0 +1,174 @@
+//! Stable storage for a Raft server.
+//!
+//! Raft needs `current_term`, `voted_for` and the log to survive a crash.
+//! Everything lives in one sqlite file per server, inside a data directory.
+//!
+//! Once there's a snapshot, the log on disk starts at the snapshot's last entry.
+
+use std::{cell::Cell, fs, path::Path};
+
+use serde_json::{from_str, to_string};
+use sqlite::{Connection, State};
+
+use crate::Operation;
+
+use super::{Config, Log};
+
+pub const SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS hard_state (id INTEGER PRIMARY KEY CHECK (id = 0), term INTEGER NOT NULL, voted_for INTEGER);
+    CREATE TABLE IF NOT EXISTS log (idx INTEGER PRIMARY KEY, entry TEXT NOT NULL);
+    CREATE TABLE IF NOT EXISTS snapshot (id INTEGER PRIMARY KEY CHECK (id = 0), idx INTEGER NOT NULL, term INTEGER NOT NULL, config TEXT NOT NULL, data BLOB NOT NULL);
+";
+pub const SAVE_STATE: &str = "INSERT OR REPLACE INTO hard_state VALUES (0, :term, :voted_for);";
+pub const RECALL_STATE: &str = "SELECT term, voted_for FROM hard_state WHERE id = 0;";
+pub const SAVE_ENTRY: &str = "INSERT OR REPLACE INTO log VALUES (:idx, :entry);";
+pub const TRUNCATE: &str = "DELETE FROM log WHERE idx >= :idx;";
+pub const RECALL_LOG: &str = "SELECT idx, entry FROM log WHERE idx >= :idx ORDER BY idx;";
+pub const SAVE_SNAPSHOT: &str =
+    "INSERT OR REPLACE INTO snapshot VALUES (0, :idx, :term, :config, :data);";
+pub const COMPACT: &str = "DELETE FROM log WHERE idx < :idx;";
+pub const RECALL_SNAPSHOT: &str = "SELECT idx, term, config, data FROM snapshot WHERE id = 0;";
+
+/// Everything found on disk.
+pub struct Recalled<O> {
+    pub term: usize,
+    pub voted_for: Option<usize>,
+    /// Index of `log[0]`. Everything up to and including it is in `snapshot`.
+    pub offset: usize,
+    pub log: Vec<Log<O>>,
+    /// Snapshot and the membership it was taken under.
+    pub snapshot: Option<(Config, Vec<u8>)>,
+}
+
+pub struct Storage {
+    db: Connection,
+    /// Last (term, vote) written, so that unchanged state does not cost a write.
+    saved: Cell<Option<(usize, Option<usize>)>>,
+}
+
+impl Storage {
+    /// Opens (or creates) `raft-{id}.db` inside `dir`.
+    pub fn open(dir: &Path, id: usize) -> Result<Self, sqlite::Error> {
+        fs::create_dir_all(dir).unwrap();
+        let db = Connection::open(dir.join(format!("raft-{id}.db")))?;
+        db.execute(SCHEMA)?;
+        Ok(Self {
+            db,
+            saved: Cell::new(None),
+        })
+    }
+
+    /// Whatever was saved before the crash. A new server gets term 0, no vote and just the sentinel entry.
+    ///
+    /// With a snapshot, `log[0]` stands in for its last entry.
+    pub fn recall<O: Operation>(&self) -> Result<Recalled<O>, sqlite::Error> {
+        let mut term = 0;
+        let mut voted_for = None;
+        let mut q = self.db.prepare(RECALL_STATE)?;
+        if let Some(row) = q.iter().next() {
+            let row = row?;
+            term = row.read::<i64, _>("term") as usize;
+            voted_for = row.read::<Option<i64>, _>("voted_for").map(|v| v as usize);
+        }
+        self.saved.set(Some((term, voted_for)));
+
+        let (mut offset, mut last_term, mut snapshot) = (0, 0, None);
+        let mut q = self.db.prepare(RECALL_SNAPSHOT)?;
+        if let Some(row) = q.iter().next() {
+            let row = row?;
+            offset = row.read::<i64, _>("idx") as usize;
+            last_term = row.read::<i64, _>("term") as usize;
+            let config = from_str::<Config>(row.read::<&str, _>("config")).unwrap();
+            snapshot = Some((config, row.read::<&[u8], _>("data").to_vec()));
+        }
+
+        // The entry at `offset` may never have been written, a leader only saves what it appends.
+        let mut log = vec![Log {
+            term: last_term,
+            command: None,
+            config: None,
+        }];
+        let mut q = self.db.prepare(RECALL_LOG)?;
+        q.bind((":idx", offset as i64))?;
+        for row in q.iter() {
+            let row = row?;
+            let idx = row.read::<i64, _>("idx") as usize;
+            let entry = from_str::<Log<O>>(row.read::<&str, _>("entry")).unwrap();
+            if idx == offset {
+                // A log that doesn't line up with the snapshot. The snapshot wins.
+                if entry.term != last_term {
+                    break;
+                }
+                continue;
+            }
+            if idx != offset + log.len() {
+                break;
+            }
+            log.push(entry);
+        }
+
+        Ok(Recalled {
+            term,
+            voted_for,
+            offset,
+            log,
+            snapshot,
+        })
+    }
+
+    /// No-op if nothing changed since the last call.
+    pub fn save_state(&self, term: usize, voted_for: Option<usize>) -> Result<(), sqlite::Error> {
+        if self.saved.get() == Some((term, voted_for)) {
+            return Ok(());
+        }
+        let mut q = self.db.prepare(SAVE_STATE)?;
+        q.bind((":term", term as i64))?;
+        q.bind((":voted_for", voted_for.map(|v| v as i64)))?;
+        while q.next()? != State::Done {}
+        self.saved.set(Some((term, voted_for)));
+        Ok(())
+    }
+
+    /// Writes entries `from..` and drops anything on disk past the end of `log`.
+    /// `log[0]` is entry `offset`. One transaction, so one fsync no matter how many entries.
+    pub fn save_log<O: Operation>(
+        &self,
+        offset: usize,
+        from: usize,
+        log: &[Log<O>],
+    ) -> Result<(), sqlite::Error> {
+        self.db.execute("BEGIN;")?;
+        for (i, entry) in log.iter().enumerate().skip(from - offset) {
+            let mut q = self.db.prepare(SAVE_ENTRY)?;
+            q.bind((":idx", (offset + i) as i64))?;
+            q.bind((":entry", &*to_string(entry).unwrap()))?;
+            while q.next()? != State::Done {}
+        }
+        let mut q = self.db.prepare(TRUNCATE)?;
+        q.bind((":idx", (offset + log.len()) as i64))?;
+        while q.next()? != State::Done {}
+        self.db.execute("COMMIT;")
+    }
+
+    /// Saves a snapshot covering everything up to `idx`, and drops the log before it.
+    /// The entry at `idx` stays, it's what `log[0]` becomes.
+    pub fn save_snapshot(
+        &self,
+        idx: usize,
+        term: usize,
+        config: &Config,
+        data: &[u8],
+    ) -> Result<(), sqlite::Error> {
+        self.db.execute("BEGIN;")?;
+        let mut q = self.db.prepare(SAVE_SNAPSHOT)?;
+        q.bind((":idx", idx as i64))?;
+        q.bind((":term", term as i64))?;
+        q.bind((":config", &*to_string(config).unwrap()))?;
+        q.bind((":data", data))?;
+        while q.next()? != State::Done {}
+        let mut q = self.db.prepare(COMPACT)?;
+        q.bind((":idx", idx as i64))?;
+        while q.next()? != State::Done {}
+        self.db.execute("COMMIT;")
+    }
+}
@@ -0,0 +1,266 @@
+//! Exactly-once execution of client commands.
+//!
+//! Clients retry, and a command can get decided more than once. [`Sessions`] sits between the
+//! log and the [`StateMachine`] and remembers the last response for every client, so a repeat
+//! gets the cached answer instead of running again.
+//!
+//! The table is part of the replicated state: every replica builds the same one,
+//! and it goes into snapshots along with the state.
+//!
+//! A client that restarts starts its op ids over, under a new random session id. Sessions are
+//! keyed on that too, so the new run starts from scratch, and the old one just expires.
+//! A repeat also only counts as one if it's the same op, in case two runs draw the same id.
+
+use std::collections::{BTreeMap, BTreeSet};
+
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+
+use crate::{Operation, StateMachine};
+
+/// Sessions idle for this many applied commands are dropped.
+/// Counted in commands, not seconds, so that every replica drops the same ones at the same point.
+pub const SESSION_TTL: usize = 1024;
+
+/// Responses kept per client. Clients pipeline, so ops can be decided out of order;
+/// this has to cover however many a client keeps in flight.
+pub const SESSION_WINDOW: usize = 128;
+
+type Output<S> = <<S as StateMachine>::Op as Operation>::Output;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(bound = "")]
+struct Session<S: StateMachine> {
+    /// Every op still remembered and its output, by op id.
+    outputs: BTreeMap<usize, (S::Op, Output<S>)>,
+    /// Everything below this has been forgotten.
+    floor: usize,
+    /// `clock` when this client was last seen.
+    last: usize,
+}
+
+impl<S: StateMachine> Session<S> {
+    fn new() -> Self {
+        Self {
+            outputs: BTreeMap::new(),
+            floor: 0,
+            last: 0,
+        }
+    }
+}
+
+/// `K` is whatever identifies one run of a client. Paxos uses `client_id` and the session id,
+/// Raft the client's address and the session id.
+pub struct Sessions<K, S: StateMachine> {
+    state: S,
+    table: BTreeMap<K, Session<S>>,
+    /// Every client by when it was last seen, so expiring doesn't have to look at the rest.
+    idle: BTreeSet<(usize, K)>,
+    /// Commands applied so far.
+    clock: usize,
+    ttl: usize,
+}
+
+impl<K: Ord + Clone, S: StateMachine> Default for Sessions<K, S> {
+    fn default() -> Self {
+        Self::with_ttl(SESSION_TTL)
+    }
+}
+
+impl<K: Ord + Clone, S: StateMachine> Sessions<K, S> {
+    /// Every replica has to use the same `ttl`.
+    pub fn with_ttl(ttl: usize) -> Self {
+        Self {
+            state: S::default(),
+            table: BTreeMap::new(),
+            idle: BTreeSet::new(),
+            clock: 0,
+            ttl,
+        }
+    }
+
+    /// The state underneath, for reads that don't go through the log.
+    pub fn state(&mut self) -> &mut S {
+        &mut self.state
+    }
+
+    /// Runs `op` unless this client already got an answer for `op_id`.
+    ///
+    /// A repeat gets the cached output. Anything that fell out of the window gets None:
+    /// it ran at some point, but we no longer know what the client was told.
+    /// A different op under a remembered id means the client restarted and somehow kept its
+    /// session id. It starts over.
+    pub fn execute(&mut self, client: K, op_id: usize, op: &S::Op) -> Option<Output<S>> {
+        self.clock += 1;
+        self.expire();
+
+        let s = self
+            .table
+            .entry(client.clone())
+            .or_insert_with(Session::new);
+        self.idle.remove(&(s.last, client.clone()));
+        self.idle.insert((self.clock, client));
+        s.last = self.clock;
+        if op_id < s.floor {
+            return None;
+        }
+        match s.outputs.get(&op_id) {
+            Some((seen, out)) if seen == op => return Some(out.clone()),
+            Some(_) => {
+                *s = Session {
+                    last: s.last,
+                    ..Session::new()
+                }
+            }
+            None => {}
+        }
+
+        let output = self.state.apply(op);
+        s.outputs.insert(op_id, (op.clone(), output.clone()));
+        if s.outputs.len() > SESSION_WINDOW {
+            let (oldest, _) = s.outputs.pop_first().unwrap();
+            s.floor = oldest + 1;
+        }
+        Some(output)
+    }
+
+    fn expire(&mut self) {
+        while let Some((last, _)) = self.idle.first() {
+            if self.clock - last <= self.ttl {
+                break;
+            }
+            let (_, client) = self.idle.pop_first().unwrap();
+            self.table.remove(&client);
+        }
+    }
+}
+
+impl<K, S> Sessions<K, S>
+where
+    K: Ord + Clone + Serialize + DeserializeOwned,
+    S: StateMachine,
+{
+    /// State and session table together.
+    pub fn snapshot(&self) -> Vec<u8> {
+        let table = self.table.iter().collect::<Vec<_>>();
+        serde_json::to_vec(&(self.state.snapshot(), table, self.clock)).unwrap()
+    }
+
+    pub fn restore(&mut self, snapshot: &[u8]) {
+        type Saved<K, S> = (Vec<u8>, Vec<(K, Session<S>)>, usize);
+        let (state, table, clock): Saved<K, S> = serde_json::from_slice(snapshot).unwrap();
+        self.state.restore(&state);
+        self.table = table.into_iter().collect();
+        self.idle = self
+            .table
+            .iter()
+            .map(|(k, s)| (s.last, k.clone()))
+            .collect();
+        self.clock = clock;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::kv::{KvOp, KvStore};
+
+    fn put(key: &str, value: &str) -> KvOp {
+        KvOp::Put {
+            key: key.into(),
+            value: value.into(),
+        }
+    }
+
+    #[test]
+    fn repeats_get_the_cached_answer() {
+        let mut s = Sessions::<usize, KvStore>::default();
+        assert_eq!(s.execute(1, 0, &put("k", "a")), Some(Ok(None)));
+        assert_eq!(s.execute(1, 1, &put("k", "b")), Some(Ok(Some("a".into()))));
+        // A retry of op 0 doesn't run again, and still says what it said the first time.
+        assert_eq!(s.execute(1, 0, &put("k", "a")), Some(Ok(None)));
+        assert_eq!(s.state().get("k"), Some(&"b".to_string()));
+        // Same op id, different client.
+        assert_eq!(s.execute(2, 0, &put("k", "c")), Some(Ok(Some("b".into()))));
+    }
+
+    #[test]
+    fn a_restarted_client_is_not_answered_from_its_last_run() {
+        let mut s = Sessions::<(usize, u64), KvStore>::default();
+        s.execute((1, 7), 0, &put("k", "a"));
+        s.execute((1, 7), 1, &put("k", "b"));
+        // Back from a restart, counting from 0 again under a new session.
+        assert_eq!(
+            s.execute((1, 8), 0, &put("k", "c")),
+            Some(Ok(Some("b".into())))
+        );
+        assert_eq!(s.state().get("k"), Some(&"c".to_string()));
+        assert_eq!(
+            s.execute((1, 8), 1, &put("k", "b")),
+            Some(Ok(Some("c".into())))
+        );
+        // Same session id drawn twice. A different op still can't get an old answer.
+        s.execute((2, 7), 0, &put("j", "a"));
+        assert_eq!(
+            s.execute((2, 7), 0, &put("j", "b")),
+            Some(Ok(Some("a".into())))
+        );
+    }
+
+    #[test]
+    fn only_the_window_is_remembered() {
+        let mut s = Sessions::<(usize, u64), KvStore>::default();
+        for i in 0..=SESSION_WINDOW {
+            s.execute((1, 7), i, &put("k", &i.to_string()));
+        }
+        // Op 0 fell out. It ran, but there's no telling what it returned.
+        assert_eq!(s.execute((1, 7), 0, &put("k", "0")), None);
+        assert_eq!(s.state().get("k"), Some(&SESSION_WINDOW.to_string()));
+        let last = SESSION_WINDOW - 1;
+        assert_eq!(
+            s.execute((1, 7), 1, &put("k", "1")),
+            Some(Ok(Some("0".into())))
+        );
+        assert!(s
+            .execute((1, 7), last, &put("k", &last.to_string()))
+            .is_some());
+        // The next run of the same client is a new session. Nothing of its is below the floor.
+        let ran = (0..3).map(|i| s.execute((1, 9), i, &put("k", "x")));
+        assert!(ran.collect::<Vec<_>>().iter().all(Option::is_some));
+    }
+
+    #[test]
+    fn idle_sessions_expire() {
+        let mut s = Sessions::<usize, KvStore>::with_ttl(3);
+        s.execute(1, 0, &put("a", "1"));
+        s.execute(2, 0, &put("b", "1"));
+        for i in 1..4 {
+            s.execute(2, i, &put("b", "2"));
+        }
+        // Client 1 has been quiet for more than 3 commands, client 2 hasn't.
+        assert!(!s.table.contains_key(&1));
+        assert!(s.table.contains_key(&2));
+        assert_eq!(s.idle.len(), 1);
+        // So its op 0 runs again.
+        assert_eq!(s.execute(1, 0, &put("a", "1")), Some(Ok(Some("1".into()))));
+    }
+
+    #[test]
+    fn snapshots_carry_the_sessions() {
+        let mut s = Sessions::<usize, KvStore>::with_ttl(4);
+        s.execute(1, 0, &put("k", "a"));
+        s.execute(2, 0, &put("k", "b"));
+
+        let mut t = Sessions::<usize, KvStore>::with_ttl(4);
+        t.restore(&s.snapshot());
+        assert_eq!(t.snapshot(), s.snapshot());
+        assert_eq!(t.execute(2, 0, &put("k", "b")), Some(Ok(Some("a".into()))));
+        assert_eq!(t.state().get("k"), Some(&"b".to_string()));
+        // Expiry picks up where it left off.
+        for i in 1..3 {
+            t.execute(2, i, &put("k", "c"));
+        }
+        assert!(t.table.contains_key(&1));
+        t.execute(2, 3, &put("k", "c"));
+        assert!(!t.table.contains_key(&1));
+    }
+}